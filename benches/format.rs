@@ -0,0 +1,85 @@
+use std::hint::black_box;
+use std::io;
+use std::time::{Duration, Instant};
+
+use prettifier::{json, xml, FormatOptions, MAX_DEPTH_LIMIT};
+
+const REQUEST: &str = include_str!("../src/testdata/request.xml");
+const RESPONSE: &str = include_str!("../src/testdata/response.xml");
+const SIRENA_REQUEST: &str = include_str!("../src/testdata/sirena_request.xml");
+const SIRENA_RESPONSE: &str = include_str!("../src/testdata/sirena_response.xml");
+const JSON_RESPONSE: &str = include_str!("../src/testdata/response.json");
+
+// сколько времени крутить каждый замер
+const BUDGET: Duration = Duration::from_millis(500);
+
+// прогоняет run, пока не истечёт бюджет, и печатает среднее время и скорость
+fn measure<T>(name: &str, body: &str, run: impl Fn(&str) -> T) {
+    let mut runs = 0u32;
+    let start = Instant::now();
+    while start.elapsed() < BUDGET || runs == 0 {
+        black_box(run(black_box(body)));
+        runs += 1;
+    }
+    let per_run = start.elapsed() / runs;
+    let speed = body.len() as f64 / per_run.as_secs_f64() / (1024.0 * 1024.0);
+
+    println!("{name:<24} {:>9} bytes {per_run:>12.2?}/iter {speed:>8.1} MiB/s", body.len());
+}
+
+// большой ответ: copies копий testdata-документа внутри одного корня
+fn xml_batch(document: &str, copies: usize) -> String {
+    let root = document.trim_start_matches(|ch| ch != '>').trim_start_matches('>');
+    format!("<?xml version=\"1.0\"?><batch>{}</batch>", root.repeat(copies))
+}
+
+fn json_batch(document: &str, copies: usize) -> String {
+    format!("[{}]", vec![document.trim(); copies].join(","))
+}
+
+// depth вложенных массивов с полем на каждом уровне
+fn json_nested(depth: usize) -> String {
+    "[{\"a\":".repeat(depth) + "1" + &"}]".repeat(depth)
+}
+
+// depth вложенных элементов с атрибутом и текстом на каждом уровне
+fn xml_nested(depth: usize) -> String {
+    "<a n=\"1\">t".repeat(depth) + &"</a>".repeat(depth)
+}
+
+fn main() {
+    let format_xml = |body: &str| xml::format(body, 4).unwrap();
+    let format_json = |body: &str| json::format(body, 4).unwrap();
+    // только разбор в дерево, без печати
+    let parse_xml = |body: &str| xml::parse(body).unwrap().root.children.len();
+    let parse_json = |body: &str| json::parse(body).is_ok();
+
+    let xml_large = xml_batch(RESPONSE, 500);
+    let json_large = json_batch(JSON_RESPONSE, 2000);
+
+    measure("xml/request", REQUEST, format_xml);
+    measure("xml/response", RESPONSE, format_xml);
+    measure("xml/sirena_request", SIRENA_REQUEST, format_xml);
+    measure("xml/sirena_response", SIRENA_RESPONSE, format_xml);
+    measure("xml/response x500", &xml_large, format_xml);
+    measure("xml/parse response x500", &xml_large, parse_xml);
+    measure("json/response", JSON_RESPONSE, format_json);
+    measure("json/response x2000", &json_large, format_json);
+    measure("json/parse response x2000", &json_large, parse_json);
+
+    // по частям из Read в Write, вывод отбрасывается
+    let stream_xml = |body: &str| xml::format_stream(body.as_bytes(), io::sink(), 4).unwrap();
+    let stream_json = |body: &str| json::format_stream(body.as_bytes(), io::sink(), 4).unwrap();
+    measure("xml/stream response x500", &xml_large, stream_xml);
+    measure("json/stream response x2000", &json_large, stream_json);
+
+    // время разбора и вывода должно расти линейно с глубиной вложенности;
+    // вывод без отступов, иначе его размер растёт как квадрат глубины
+    let minify = FormatOptions::new().minify(true).max_depth(MAX_DEPTH_LIMIT);
+    let format_xml = |body: &str| xml::format(body, minify).unwrap();
+    let format_json = |body: &str| json::format(body, minify).unwrap();
+    for depth in [32, 64, 128] {
+        measure(&format!("xml/nested {depth}"), &xml_nested(depth), format_xml);
+        measure(&format!("json/nested {depth}"), &json_nested(depth), format_json);
+    }
+}
@@ -0,0 +1,293 @@
+use std::borrow::Cow;
+
+use super::{offset_in, xml, FormatError};
+
+// кодировка входа и вывода format_bytes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Encoding {
+    Utf8,
+    Utf16Le,
+    Utf16Be,
+    Windows1251,
+    // iso-8859-1: байт - код символа
+    Latin1,
+}
+
+// символы байтов 0x80..=0xbf в windows-1251, байты 0xc0..=0xff - буквы
+// от 'А' до 'я' подряд. Неиспользуемый байт 0x98 отображается в U+0098,
+// как в WHATWG, чтобы любой вход раскодировался и кодировался обратно
+const WINDOWS_1251: [char; 64] = [
+    '\u{0402}', '\u{0403}', '\u{201a}', '\u{0453}', '\u{201e}', '\u{2026}', '\u{2020}', '\u{2021}',
+    '\u{20ac}', '\u{2030}', '\u{0409}', '\u{2039}', '\u{040a}', '\u{040c}', '\u{040b}', '\u{040f}',
+    '\u{0452}', '\u{2018}', '\u{2019}', '\u{201c}', '\u{201d}', '\u{2022}', '\u{2013}', '\u{2014}',
+    '\u{0098}', '\u{2122}', '\u{0459}', '\u{203a}', '\u{045a}', '\u{045c}', '\u{045b}', '\u{045f}',
+    '\u{00a0}', '\u{040e}', '\u{045e}', '\u{0408}', '\u{00a4}', '\u{0490}', '\u{00a6}', '\u{00a7}',
+    '\u{0401}', '\u{00a9}', '\u{0404}', '\u{00ab}', '\u{00ac}', '\u{00ad}', '\u{00ae}', '\u{0407}',
+    '\u{00b0}', '\u{00b1}', '\u{0406}', '\u{0456}', '\u{0491}', '\u{00b5}', '\u{00b6}', '\u{00b7}',
+    '\u{0451}', '\u{2116}', '\u{0454}', '\u{00bb}', '\u{0458}', '\u{0405}', '\u{0455}', '\u{0457}',
+];
+
+// объявление xml ищется только в начале входа
+const DECLARATION_LEN: usize = 1024;
+
+impl Encoding {
+    // кодировка по имени из объявления xml или charset, регистр не важен
+    pub fn from_label(label: &str) -> Option<Encoding> {
+        match label.trim().to_ascii_lowercase().as_str() {
+            "utf-8" | "utf8" => Some(Encoding::Utf8),
+            "utf-16" | "utf-16le" => Some(Encoding::Utf16Le),
+            "utf-16be" => Some(Encoding::Utf16Be),
+            "windows-1251" | "cp1251" | "x-cp1251" => Some(Encoding::Windows1251),
+            "iso-8859-1" | "latin1" | "l1" => Some(Encoding::Latin1),
+            _ => None,
+        }
+    }
+
+    // имя для объявления xml
+    pub fn name(&self) -> &'static str {
+        match self {
+            Encoding::Utf8 => "UTF-8",
+            Encoding::Utf16Le | Encoding::Utf16Be => "UTF-16",
+            Encoding::Windows1251 => "windows-1251",
+            Encoding::Latin1 => "ISO-8859-1",
+        }
+    }
+
+    // метка порядка байтов в начале текста
+    fn bom(&self) -> &'static [u8] {
+        match self {
+            Encoding::Utf8 => b"\xef\xbb\xbf",
+            Encoding::Utf16Le => b"\xff\xfe",
+            Encoding::Utf16Be => b"\xfe\xff",
+            Encoding::Windows1251 | Encoding::Latin1 => b"",
+        }
+    }
+
+    // кодировка входа и длина метки порядка байтов в его начале: явно
+    // заданная, иначе по метке, иначе из объявления xml, иначе utf-8
+    pub(crate) fn detect(
+        body: &[u8],
+        explicit: Option<Encoding>,
+    ) -> Result<(Encoding, usize), FormatError> {
+        let with_bom = |encoding: Encoding| {
+            let bom = encoding.bom();
+            let len = if body.starts_with(bom) { bom.len() } else { 0 };
+            (encoding, len)
+        };
+
+        if let Some(encoding) = explicit {
+            return Ok(with_bom(encoding));
+        }
+        let bom = [Encoding::Utf8, Encoding::Utf16Le, Encoding::Utf16Be]
+            .into_iter()
+            .find(|encoding| body.starts_with(encoding.bom()));
+        if let Some(encoding) = bom {
+            return Ok(with_bom(encoding));
+        }
+
+        // объявление записано в ascii, поэтому начало читается как latin-1
+        let start: String = body.iter().take(DECLARATION_LEN).map(|&b| char::from(b)).collect();
+        let trimmed = start.trim_start();
+        let Some(label) = xml::declared_encoding(trimmed) else {
+            return Ok((Encoding::Utf8, 0));
+        };
+        match Encoding::from_label(label) {
+            // utf-16 без метки не прочитался бы как ascii: объявление неверно,
+            // а вход, скорее всего, в utf-8
+            Some(Encoding::Utf16Le | Encoding::Utf16Be) => Ok((Encoding::Utf8, 0)),
+            Some(encoding) => Ok((encoding, 0)),
+            None => {
+                let offset = start.len() - trimmed.len() + offset_in(trimmed, label);
+                let message = format!("unsupported encoding `{}`", label);
+                Err(FormatError::encoding(&start, offset, message))
+            }
+        }
+    }
+
+    // текст из байтов без метки порядка байтов; смещение ошибки - в байтах body
+    pub(crate) fn decode(&self, body: &[u8]) -> Result<String, FormatError> {
+        match self {
+            Encoding::Utf8 => Ok(String::from_utf8(body.to_vec())?),
+            Encoding::Utf16Le => decode_utf16(body, u16::from_le_bytes),
+            Encoding::Utf16Be => decode_utf16(body, u16::from_be_bytes),
+            Encoding::Windows1251 => Ok(body
+                .iter()
+                .map(|&b| match b {
+                    0..=0x7f => char::from(b),
+                    0x80..=0xbf => WINDOWS_1251[usize::from(b - 0x80)],
+                    // проверено диапазоном
+                    _ => char::from_u32(0x410 + u32::from(b - 0xc0)).unwrap_or_default(),
+                })
+                .collect()),
+            Encoding::Latin1 => Ok(body.iter().map(|&b| char::from(b)).collect()),
+        }
+    }
+
+    // байты текста; символ, которого нет в кодировке, - ошибка в его позиции
+    pub(crate) fn encode(&self, text: &str) -> Result<Vec<u8>, FormatError> {
+        let unit = |ch: char| -> Option<u8> {
+            match (self, u32::from(ch)) {
+                (_, 0..=0x7f) | (Encoding::Latin1, 0x80..=0xff) => u8::try_from(ch).ok(),
+                (Encoding::Windows1251, code @ 0x410..=0x44f) => Some((code - 0x410 + 0xc0) as u8),
+                (Encoding::Windows1251, _) => {
+                    let i = WINDOWS_1251.iter().position(|&c| c == ch)?;
+                    u8::try_from(0x80 + i).ok()
+                }
+                _ => None,
+            }
+        };
+
+        match self {
+            Encoding::Utf8 => Ok(text.as_bytes().to_vec()),
+            Encoding::Utf16Le => Ok(text.encode_utf16().flat_map(u16::to_le_bytes).collect()),
+            Encoding::Utf16Be => Ok(text.encode_utf16().flat_map(u16::to_be_bytes).collect()),
+            Encoding::Windows1251 | Encoding::Latin1 => {
+                let mut res = Vec::with_capacity(text.len());
+                for (i, ch) in text.char_indices() {
+                    let Some(b) = unit(ch) else {
+                        let message = format!(
+                            "character U+{:04X} cannot be encoded in {}",
+                            u32::from(ch),
+                            self.name()
+                        );
+                        return Err(FormatError::encoding(text, i, message));
+                    };
+                    res.push(b);
+                }
+                Ok(res)
+            }
+        }
+    }
+}
+
+fn decode_utf16(body: &[u8], unit: fn([u8; 2]) -> u16) -> Result<String, FormatError> {
+    let units = body.chunks_exact(2).map(|pair| unit([pair[0], pair[1]]));
+    let mut res = String::with_capacity(body.len() / 2);
+    // смещение в байтах следующего символа
+    let mut offset = 0;
+    for ch in char::decode_utf16(units) {
+        let Ok(ch) = ch else {
+            let message = "unpaired utf-16 surrogate".to_string();
+            let mut error = FormatError::encoding(&res, res.len(), message);
+            error.offset = offset;
+            return Err(error);
+        };
+        res.push(ch);
+        offset += 2 * ch.len_utf16();
+    }
+
+    if !body.len().is_multiple_of(2) {
+        let message = "incomplete utf-16 code unit at the end of input".to_string();
+        let mut error = FormatError::encoding(&res, res.len(), message);
+        error.offset = offset;
+        return Err(error);
+    }
+    Ok(res)
+}
+
+// объявление xml в начале body называет encoding вместо кодировки входа
+pub(crate) fn redeclare(body: &str, encoding: Encoding) -> Cow<'_, str> {
+    let trimmed = body.trim_start();
+    let Some(label) = xml::declared_encoding(trimmed) else {
+        return Cow::Borrowed(body);
+    };
+    if Encoding::from_label(label) == Some(encoding) {
+        return Cow::Borrowed(body);
+    }
+
+    let start = body.len() - trimmed.len() + offset_in(trimmed, label);
+    let end = start + label.len();
+    Cow::Owned(format!("{}{}{}", &body[..start], encoding.name(), &body[end..]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Encoding, WINDOWS_1251};
+    use crate::{format_bytes, ErrorKind, FormatOptions};
+
+    #[test]
+    fn windows_1251() {
+        let all: Vec<u8> = (0..=255).collect();
+        let text = Encoding::Windows1251.decode(&all).unwrap();
+        assert_eq!(256, text.chars().count());
+        assert!(text.contains("АБВ") && text.ends_with("эюя") && text.contains('Ё'));
+        assert_eq!(all, Encoding::Windows1251.encode(&text).unwrap());
+
+        let mut chars = WINDOWS_1251.to_vec();
+        chars.sort();
+        chars.dedup();
+        assert_eq!(WINDOWS_1251.len(), chars.len());
+
+        let err = Encoding::Windows1251.encode("a\n€ā").unwrap_err();
+        assert_eq!(ErrorKind::Encoding, err.kind());
+        assert_eq!((5, 2, 2), (err.offset(), err.line(), err.column()));
+        assert_eq!("character U+0101 cannot be encoded in windows-1251", err.message());
+    }
+
+    #[test]
+    fn utf16() {
+        let text = "<a>ёж 𝄞</a>";
+        for encoding in [Encoding::Utf16Le, Encoding::Utf16Be] {
+            let bytes = encoding.encode(text).unwrap();
+            assert_eq!(text, encoding.decode(&bytes).unwrap());
+
+            let err = encoding.decode(&bytes[..bytes.len() - 1]).unwrap_err();
+            assert_eq!(ErrorKind::Encoding, err.kind());
+            assert_eq!(bytes.len() - 2, err.offset());
+        }
+
+        // одиночный суррогат после "<a>"
+        let err = Encoding::Utf16Le.decode(b"<\0a\0>\0\x00\xd8<\0").unwrap_err();
+        assert_eq!((6, 1, 4), (err.offset(), err.line(), err.column()));
+    }
+
+    #[test]
+    fn detect() {
+        let detect = |body: &[u8]| Encoding::detect(body, None).unwrap();
+        assert_eq!((Encoding::Utf8, 0), detect(b"{}"));
+        assert_eq!((Encoding::Utf8, 3), detect(b"\xef\xbb\xbf{}"));
+        assert_eq!((Encoding::Utf16Le, 2), detect(b"\xff\xfe{\0}\0"));
+        assert_eq!((Encoding::Utf16Be, 2), detect(b"\xfe\xff\0{\0}"));
+        let declared = b"  <?xml version='1.0' encoding = 'CP1251'?><a>\xc0</a>";
+        assert_eq!((Encoding::Windows1251, 0), detect(declared));
+        assert_eq!((Encoding::Utf8, 0), detect(b"<?xml version=\"1.0\" encoding=\"UTF-16\"?><a/>"));
+        assert_eq!((Encoding::Utf8, 0), detect(b"<?xml-stylesheet encoding=\"cp1251\"?><a/>"));
+
+        // явно заданная кодировка важнее метки и объявления
+        let explicit = Encoding::detect(b"\xef\xbb\xbf<a/>", Some(Encoding::Latin1)).unwrap();
+        assert_eq!((Encoding::Latin1, 0), explicit);
+
+        let declared = b"<?xml version=\"1.0\" encoding=\"koi8-r\"?>";
+        let err = Encoding::detect(declared, None).unwrap_err();
+        assert_eq!(ErrorKind::Encoding, err.kind());
+        assert_eq!((30, 1, 31), (err.offset(), err.line(), err.column()));
+        assert_eq!("unsupported encoding `koi8-r`", err.message());
+    }
+
+    #[test]
+    fn bytes() {
+        let body = b"<?xml version=\"1.0\" encoding=\"windows-1251\"?>\
+            <a><b>\xcf\xf0\xe8\xe2\xe5\xf2</b></a>";
+        let utf8 = "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n<a>\n    <b>Привет</b>\n</a>\n";
+        assert_eq!(utf8.as_bytes(), format_bytes(body, 4).unwrap());
+
+        let res = format_bytes(body, FormatOptions::new().keep_encoding(true)).unwrap();
+        let expected = utf8.replace("UTF-8", "windows-1251");
+        assert_eq!(Encoding::Windows1251.encode(&expected).unwrap(), res);
+
+        // utf-16 с меткой: метка остаётся только в кодировке входа
+        let mut body = b"\xff\xfe".to_vec();
+        body.extend(Encoding::Utf16Le.encode("{\"a\": [\"ё\"]}").unwrap());
+        let expected = "{\n  \"a\": [\n    \"ё\"\n  ]\n}";
+        assert_eq!(expected.as_bytes(), format_bytes(&body, 2).unwrap());
+        let res = format_bytes(&body, FormatOptions::new().indent(2).keep_encoding(true)).unwrap();
+        assert_eq!(b"\xff\xfe".as_slice(), &res[..2]);
+        assert_eq!(expected, Encoding::Utf16Le.decode(&res[2..]).unwrap());
+
+        let latin1 = FormatOptions::new().encoding(Encoding::Latin1);
+        assert_eq!("<a>é</a>\n".as_bytes(), format_bytes(b"<a>\xe9</a>", latin1).unwrap());
+
+        let err = format_bytes(b"<a>\xe9</a>", 4).unwrap_err();
+        assert_eq!((ErrorKind::Encoding, 3), (err.kind(), err.offset()));
+    }
+}
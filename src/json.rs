@@ -1,529 +1,1511 @@
+use std::borrow::Cow;
+use std::cell::Cell;
+use std::cmp::Ordering;
+use std::io::{Read, Write};
 use std::prelude::v1::*;
 
+use super::stream::{self, Formatter, Machine, Stream, LOOKAHEAD};
 use super::{
-    unescape_utf8, And, AnyChar, AnyExcept, BoxedParser, CharSequence, Ignore, Or, Parser,
-    ZeroOrMore, ZeroOrOne,
+    consumed, escape_canonical, escape_non_ascii, format_lenient_as, furthest_of, parse_document,
+    unescape, unescape_all, unescape_utf8, And, AnyChar, CharSequence, Format, FormatError,
+    FormatOptions, Label, Map, Or, Output, ParseError, ParseResult, Parser, SurrogatePolicy,
+    ZeroOrOne,
 };
+use std::collections::HashSet;
+
+// как выводить экранированные символы в строках и ключах
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum EscapeMode {
+    // оставить как во входе
+    Preserve,
+    // раскодировать \uXXXX в utf-8
+    #[default]
+    DecodeUnicode,
+    // раскодировать все последовательности, включая \n и \", для чтения человеком,
+    // результат может не быть корректным json
+    DecodeAll,
+    // экранировать все символы вне ascii
+    AsciiOnly,
+    // как требует RFC 8785: экранированы только кавычка, обратный слеш
+    // и управляющие символы, остальное - в utf-8
+    Canonical,
+}
+
+impl EscapeMode {
+    // строка вместе с кавычками
+    fn apply(&self, raw: &str) -> String {
+        match self {
+            EscapeMode::Preserve => raw.to_string(),
+            EscapeMode::DecodeUnicode => unescape_utf8(raw),
+            EscapeMode::DecodeAll => unescape_all(raw),
+            EscapeMode::AsciiOnly => escape_non_ascii(raw),
+            EscapeMode::Canonical => escape_canonical(raw),
+        }
+    }
+}
 
-pub fn format(body: &str, ident: usize) -> Result<String, String> {
-    let mapper = move |parsed: &str, level: usize| " ".repeat(ident * level) + parsed + "\n";
-    let parser = Value::new(&mapper, 0);
-    let res = parser.parse(body);
+// порядок полей объекта на выходе
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum KeyOrder {
+    // как во входе
+    #[default]
+    Input,
+    // по байтам utf-8 раскодированного ключа
+    Bytewise,
+    // как Bytewise, но последовательности цифр сравниваются как числа: a2 < a10
+    Natural,
+    // по кодовым единицам utf-16, как требует RFC 8785
+    Utf16,
+}
 
-    if let Ok(parsed) = res.0 {
-        Ok(parsed)
+impl KeyOrder {
+    // ключи сравниваются раскодированными, без кавычек
+    fn compare(&self, a: &str, b: &str) -> Ordering {
+        match self {
+            KeyOrder::Input => Ordering::Equal,
+            KeyOrder::Bytewise => a.cmp(b),
+            KeyOrder::Natural => natural_cmp(a, b),
+            KeyOrder::Utf16 => a.encode_utf16().cmp(b.encode_utf16()),
+        }
+    }
+}
+
+// сравнение строк, в котором числа внутри сравниваются по значению
+fn natural_cmp(a: &str, b: &str) -> Ordering {
+    let mut a = a;
+    let mut b = b;
+    loop {
+        let (Some(x), Some(y)) = (a.chars().next(), b.chars().next()) else {
+            return a.len().cmp(&b.len());
+        };
+
+        if x.is_ascii_digit() && y.is_ascii_digit() {
+            let x_len = a.find(|ch: char| !ch.is_ascii_digit()).unwrap_or(a.len());
+            let y_len = b.find(|ch: char| !ch.is_ascii_digit()).unwrap_or(b.len());
+            let x_num = a[..x_len].trim_start_matches('0');
+            let y_num = b[..y_len].trim_start_matches('0');
+            let order = x_num.len().cmp(&y_num.len()).then(x_num.cmp(y_num));
+            if order != Ordering::Equal {
+                return order;
+            }
+            a = &a[x_len..];
+            b = &b[y_len..];
+        } else {
+            if x != y {
+                return x.cmp(&y);
+            }
+            a = &a[x.len_utf8()..];
+            b = &b[y.len_utf8()..];
+        }
+    }
+}
+
+// запись числа, как её строит ECMAScript Number.prototype.toString (RFC 8785)
+fn ecmascript_number(value: f64) -> String {
+    if value == 0.0 {
+        return "0".to_string();
+    }
+
+    // {:e} даёт кратчайшую последовательность цифр, однозначно задающую число
+    let scientific = format!("{:e}", value.abs());
+    let (mantissa, exp) = scientific.split_once('e').unwrap_or((&scientific, "0"));
+    let digits: String = mantissa.chars().filter(|ch| *ch != '.').collect();
+    let k = digits.len() as i32;
+    let n = exp.parse::<i32>().unwrap_or(0) + 1;
+
+    let res = if k <= n && n <= 21 {
+        digits + &"0".repeat((n - k) as usize)
+    } else if 0 < n && n <= 21 {
+        format!("{}.{}", &digits[..n as usize], &digits[n as usize..])
+    } else if -6 < n && n <= 0 {
+        format!("0.{}{}", "0".repeat(-n as usize), digits)
     } else {
-        Ok(body.to_string())
+        let sign = if n > 0 { '+' } else { '-' };
+        let mantissa = match k {
+            1 => digits,
+            _ => format!("{}.{}", &digits[..1], &digits[1..]),
+        };
+        format!("{}e{}{}", mantissa, sign, (n - 1).abs())
+    };
+
+    if value < 0.0 {
+        format!("-{}", res)
+    } else {
+        res
     }
 }
 
-// ключ в объекте
-struct Key<'a> {
-    p: BoxedParser<'a>,
+// настройки вывода json
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct JsonOptions {
+    pub escape: EscapeMode,
+    pub sort_keys: KeyOrder,
+    // числа в записи ECMAScript, литералы true/false/null в нижнем регистре.
+    // Вход при этом проверяется строже: числа в диапазоне double, запятые
+    // между элементами обязательны, а перед скобкой недопустимы. Как требует
+    // I-JSON (RFC 7493), в строках нет одиночных суррогатов, ключи не повторяются
+    pub normalize: bool,
+}
+
+impl JsonOptions {
+    // каноническая форма RFC 8785 (JCS), вместе с FormatOptions::minify
+    pub fn canonical() -> Self {
+        Self {
+            escape: EscapeMode::Canonical,
+            sort_keys: KeyOrder::Utf16,
+            normalize: true,
+        }
+    }
+}
+
+// значение json. Строки, числа и литералы хранятся в записи входа, чтобы
+// печать могла вывести их без изменений или нормализовать по настройкам.
+// После разбора это срезы входа, изменённое дерево может хранить свои строки
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum JsonValue<'a> {
+    // строка в кавычках, экранирование как во входе
+    String(Cow<'a, str>),
+    Number(Cow<'a, str>),
+    // true, false или null, допускается запись в верхнем регистре
+    Literal(Cow<'a, str>),
+    Array(Vec<JsonValue<'a>>),
+    // поля в порядке входа
+    Object(Vec<JsonMember<'a>>),
 }
 
-impl<'a> Key<'a> {
+// поле объекта: ключ в кавычках как во входе, пробелы после двоеточия, которые
+// печать без minify выводит как во входе, и значение
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct JsonMember<'a> {
+    pub key: Cow<'a, str>,
+    pub space: Cow<'a, str>,
+    pub value: JsonValue<'a>,
+}
+
+impl<'a> JsonValue<'a> {
+    // раскодированная строка без кавычек
+    pub fn as_str(&self) -> Option<String> {
+        match self {
+            JsonValue::String(raw) => Some(decode(raw)),
+            _ => None,
+        }
+    }
+
+    // значение поля объекта по раскодированному ключу, при повторах - первое
+    pub fn get(&self, key: &str) -> Option<&JsonValue<'a>> {
+        match self {
+            JsonValue::Object(members) => members
+                .iter()
+                .find(|member| decode(&member.key) == key)
+                .map(|member| &member.value),
+            _ => None,
+        }
+    }
+}
+
+// содержимое строки в кавычках со всеми раскрытыми escape-последовательностями
+fn decode(raw: &str) -> String {
+    unescape_all(&raw[1..raw.len() - 1])
+}
+
+// разбор документа в дерево
+pub fn parse(body: &str) -> Result<JsonValue<'_>, FormatError> {
+    parse_with(body, FormatOptions::new())
+}
+
+// то же с ограничением вложенности options.max_depth, остальные настройки
+// на разбор не влияют
+pub fn parse_with(
+    body: &str,
+    options: impl Into<FormatOptions>,
+) -> Result<JsonValue<'_>, FormatError> {
+    parse_document(&Document::new(false, options.into().max_depth), body)
+}
+
+// вывод дерева с отступами и переводами строк из options
+pub fn print(value: &JsonValue, options: impl Into<FormatOptions>) -> String {
+    let options = options.into();
+    options.finish(print_value(value, 0, &options))
+}
+
+pub fn format(body: &str, options: impl Into<FormatOptions>) -> Result<String, FormatError> {
+    let options = options.into().for_input(body);
+    // при нормализации число вне диапазона double - ошибка разбора
+    let document = Document::new(options.json.normalize, options.max_depth);
+    parse_document(&document, body).map(|value| print(&value, options))
+}
+
+// каноническая запись по RFC 8785 (JCS): одна строка, поля отсортированы,
+// числа и строки записаны единственным способом. Результат зависит только от
+// значения документа, поэтому годится для сравнения и подписи
+pub fn canonicalize(body: &str) -> Result<String, FormatError> {
+    canonicalize_with(body, FormatOptions::new())
+}
+
+// то же с ограничением вложенности options.max_depth
+pub fn canonicalize_with(
+    body: &str,
+    options: impl Into<FormatOptions>,
+) -> Result<String, FormatError> {
+    let options = FormatOptions::new()
+        .minify(true)
+        .json(JsonOptions::canonical())
+        .max_depth(options.into().max_depth);
+    format(body, options)
+}
+
+// при ошибке разбора возвращает вход без изменений
+pub fn format_lenient(body: &str, options: impl Into<FormatOptions>) -> String {
+    format_lenient_as(body, Format::Json, options)
+}
+
+// строка в кавычках по RFC 8259, результат - строка как есть, вместе с кавычками
+struct JsonString {}
+
+impl JsonString {
     fn new() -> Self {
-        let start = CharSequence::new(String::from("\""));
-        let end = CharSequence::new(String::from("\""));
-        let content = |ch: char| ch.is_alphanumeric() || ch == '-' || ch == '_' || ch == '@';
+        Self {}
+    }
+}
+
+impl<'a> Parser<'a> for JsonString {
+    fn parse(&self, in_string: &'a str) -> ParseResult<'a> {
+        let error = |at: usize, expected: &'static str| {
+            ParseResult::err(ParseError::new(&in_string[at..], expected), in_string)
+        };
+
+        if !in_string.starts_with('"') {
+            return error(0, "`\"`");
+        }
+
+        let mut chars = in_string.char_indices().skip(1);
+        while let Some((i, ch)) = chars.next() {
+            match ch {
+                '"' => {
+                    let end = i + 1;
+                    return ParseResult::ok(&in_string[..end], &in_string[end..]);
+                }
+                '\\' => match chars.next() {
+                    Some((_, '"' | '\\' | '/' | 'b' | 'f' | 'n' | 'r' | 't')) => {}
+                    Some((j, 'u')) => {
+                        for k in j + 1..j + 5 {
+                            match chars.next() {
+                                Some((_, ch)) if ch.is_ascii_hexdigit() => {}
+                                _ => return error(k.min(in_string.len()), "hex digit"),
+                            }
+                        }
+                    }
+                    Some((j, _)) => return error(j, "escape sequence"),
+                    None => return error(in_string.len(), "escape sequence"),
+                },
+                // управляющие символы допустимы только в экранированном виде
+                ch if (ch as u32) < 0x20 => return error(i, "escaped control character"),
+                _ => {}
+            }
+        }
 
-        let mut obj = And::new();
-        obj.add_parser(start);
-        obj.add_parser(AnyChar::new(content));
-        obj.add_parser(end);
+        error(in_string.len(), "`\"`")
+    }
+}
+
+// ключ в объекте
+struct Key {
+    p: Label<JsonString>,
+}
 
+impl Key {
+    fn new() -> Self {
         Self {
-            p: BoxedParser::new(obj),
+            p: Label::new(JsonString::new(), "key"),
         }
     }
 }
 
-impl<'a> Parser for Key<'a> {
-    fn parse<'b>(&self, in_string: &'b str) -> (Result<String, ()>, &'b str) {
+impl<'a> Parser<'a> for Key {
+    fn parse(&self, in_string: &'a str) -> ParseResult<'a> {
         self.p.parse(in_string)
     }
 }
 
 // значение в массиве или объекте
-struct SpecialValue {}
+struct SpecialValue {
+    p: Or,
+}
 
 impl SpecialValue {
     fn new() -> Self {
-        Self {}
+        let mut p: Or = Or::new();
+        p.add_parser(CharSequence::new("true"));
+        p.add_parser(CharSequence::new("TRUE"));
+        p.add_parser(CharSequence::new("false"));
+        p.add_parser(CharSequence::new("FALSE"));
+        p.add_parser(CharSequence::new("null"));
+        p.add_parser(CharSequence::new("NULL"));
+
+        Self { p }
     }
 }
 
-impl Parser for SpecialValue {
-    fn parse<'b>(&self, in_string: &'b str) -> (Result<String, ()>, &'b str) {
-        let mut p = Or::new();
-        p.add_parser(CharSequence::new(String::from("true")));
-        p.add_parser(CharSequence::new(String::from("TRUE")));
-        p.add_parser(CharSequence::new(String::from("false")));
-        p.add_parser(CharSequence::new(String::from("FALSE")));
-        p.add_parser(CharSequence::new(String::from("null")));
-        p.add_parser(CharSequence::new(String::from("NULL")));
-
-        p.parse(in_string)
+impl<'a> Parser<'a> for SpecialValue {
+    fn parse(&self, in_string: &'a str) -> ParseResult<'a> {
+        self.p.parse(in_string)
     }
 }
 
-// строка, нужно только для исправления кодировки utf8
-struct StringValue {}
+// число по RFC 8259, выводится в точности так, как записано во входе
+struct Number {
+    p: And,
+}
 
-impl StringValue {
+impl Number {
     fn new() -> Self {
-        Self {}
+        let digits = || Label::new(AnyChar::new(|ch: char| ch.is_ascii_digit()), "digit");
+
+        // ведущие нули не допускаются
+        let mut int: Or = Or::new();
+        int.add_parser(CharSequence::new("0"));
+        int.add_parser(digits());
+
+        let mut frac = And::new();
+        frac.add_parser(CharSequence::new("."));
+        frac.add_parser(digits());
+
+        let mut exp_sign: Or = Or::new();
+        exp_sign.add_parser(CharSequence::new("+"));
+        exp_sign.add_parser(CharSequence::new("-"));
+
+        let mut exp = And::new();
+        exp.add_parser(AnyChar::new(|ch: char| ch == 'e' || ch == 'E'));
+        exp.add_parser(ZeroOrOne::new(exp_sign));
+        exp.add_parser(digits());
+
+        let mut p = And::new();
+        p.add_parser(ZeroOrOne::new(CharSequence::new("-")));
+        p.add_parser(Label::new(int, "digit"));
+        // необязательные части не попадают в список ожидаемого после числа
+        p.add_parser(ZeroOrOne::new(Label::hidden(frac)));
+        p.add_parser(ZeroOrOne::new(Label::hidden(exp)));
+
+        Self { p }
     }
 }
 
-impl Parser for StringValue {
-    fn parse<'b>(&self, in_string: &'b str) -> (Result<String, ()>, &'b str) {
-        let start = CharSequence::new(String::from("\""));
-        let end = CharSequence::new(String::from("\""));
-        let mut p = And::new();
-        p.add_parser(start);
-        p.add_parser(ZeroOrOne::new(AnyExcept::new(String::from("\""))));
-        p.add_parser(end);
+impl<'a> Parser<'a> for Number {
+    fn parse(&self, in_string: &'a str) -> ParseResult<'a> {
+        match self.p.parse(in_string) {
+            // цифра после ведущего нуля - ошибка, а не второе число: запятые
+            // между элементами необязательны, и "01" стало бы [0, 1]
+            ParseResult(Ok(r), rest, _)
+                if r.trim_start_matches('-') == "0"
+                    && rest.starts_with(|ch: char| ch.is_ascii_digit()) =>
+            {
+                let error = ParseError::new(rest, "`.`, `e` or end of number");
+                ParseResult::err(error, in_string)
+            }
+            res => res,
+        }
+    }
+}
 
-        let res = p.parse(in_string);
-        if let Ok(r) = res.0 {
-            (Ok(unescape_utf8(&r)), res.1)
-        } else {
-            res
+// результат парсеров значения для входа с любым временем жизни
+struct Values;
+
+impl Output for Values {
+    type Of<'a> = JsonValue<'a>;
+}
+
+// разобранные лексемы - срезы входа
+impl JsonValue<'_> {
+    fn string(s: &str) -> JsonValue<'_> {
+        JsonValue::String(Cow::Borrowed(s))
+    }
+
+    fn number(s: &str) -> JsonValue<'_> {
+        JsonValue::Number(Cow::Borrowed(s))
+    }
+
+    fn literal(s: &str) -> JsonValue<'_> {
+        JsonValue::Literal(Cow::Borrowed(s))
+    }
+}
+
+// значение в массиве или объекте; strict - вход по I-JSON, число вне диапазона
+// double, одиночный суррогат в строке и повтор ключа - ошибка.
+// Лексемы строятся один раз, объекты и массивы на любой глубине вложенности
+// разбираются через ссылку на этот же Value. Объекты и массивы глубже
+// max_depth - ошибка, depth - вложенность разбираемого сейчас значения
+struct Value {
+    scalar: Label<Or<Values>>,
+    key: Key,
+    strict: bool,
+    max_depth: usize,
+    depth: Cell<usize>,
+}
+
+impl Value {
+    fn new(strict: bool, max_depth: usize) -> Self {
+        let mut scalar: Or<Values> = Or::new();
+        scalar.add_parser(Map::new(JsonString::new(), JsonValue::string));
+        scalar.add_parser(Map::new(Number::new(), JsonValue::number));
+        scalar.add_parser(Map::new(SpecialValue::new(), JsonValue::literal));
+
+        Self {
+            scalar: Label::new(scalar, "value"),
+            key: Key::new(),
+            strict,
+            max_depth,
+            depth: Cell::new(0),
+        }
+    }
+
+    // объект или массив на следующем уровне вложенности
+    fn nested<'a>(
+        &self,
+        in_string: &'a str,
+        parser: impl Parser<'a, JsonValue<'a>>,
+    ) -> ParseResult<'a, JsonValue<'a>> {
+        let depth = self.depth.get();
+        if depth >= self.max_depth {
+            return ParseResult::err(ParseError::too_deep(in_string, self.max_depth), in_string);
+        }
+
+        self.depth.set(depth + 1);
+        let res = parser.parse(in_string);
+        self.depth.set(depth);
+        res
+    }
+
+    // при strict - ошибка на одиночном суррогате в строке raw в начале in_string
+    fn lone_surrogate<'a>(&self, raw: &str, in_string: &'a str) -> Option<ParseError<'a>> {
+        if !self.strict {
+            return None;
         }
+        let at = unescape(raw, SurrogatePolicy::Error).err()?;
+        Some(ParseError::new(&in_string[at..], "surrogate pair"))
+    }
+
+    // при strict - ошибка на повторе ключа key среди уже разобранных keys
+    fn duplicate<'a>(
+        &self,
+        keys: &mut HashSet<String>,
+        key: &str,
+        in_string: &'a str,
+    ) -> Option<ParseError<'a>> {
+        if !self.strict || keys.insert(decode(key)) {
+            return None;
+        }
+        Some(ParseError::new(in_string, format!("key other than `{}`", key)))
     }
 }
 
-// значение в массиве или объекте
-struct Value<'a, M> {
-    mapper: &'a M,
-    level: usize,
+impl<'a> Parser<'a, JsonValue<'a>> for Value {
+    fn parse(&self, in_string: &'a str) -> ParseResult<'a, JsonValue<'a>> {
+        let res = match in_string.chars().next() {
+            Some('{') => self.nested(in_string, Object::new(self)),
+            Some('[') => self.nested(in_string, Array::new(self)),
+            _ => self.scalar.parse(in_string),
+        };
+        match res {
+            ParseResult(Ok(JsonValue::Number(number)), _, _)
+                if self.strict && !number.parse::<f64>().is_ok_and(f64::is_finite) =>
+            {
+                ParseResult::err(ParseError::new(in_string, "finite number"), in_string)
+            }
+            ParseResult(Ok(JsonValue::String(ref raw)), _, _) => {
+                match self.lone_surrogate(raw, in_string) {
+                    Some(error) => ParseResult::err(error, in_string),
+                    None => res,
+                }
+            }
+            res => res,
+        }
+    }
+}
+
+// поле объекта вместе с необязательной запятой после него; keys - ключи
+// уже разобранных полей объекта для проверки повторов
+struct KeyAndValue<'v> {
+    value: &'v Value,
+    keys: Cell<HashSet<String>>,
 }
 
-impl<'a, M> Value<'a, M> {
-    fn new(mapper: &'a M, level: usize) -> Self {
-        Self { mapper, level }
+impl<'v> KeyAndValue<'v> {
+    fn new(value: &'v Value) -> Self {
+        Self {
+            value,
+            keys: Cell::new(HashSet::new()),
+        }
     }
 }
 
-impl<'a, M> Parser for Value<'a, M>
-where
-    M: for<'c> Fn(&'c str, usize) -> String + Clone,
-{
-    fn parse<'b>(&self, in_string: &'b str) -> (Result<String, ()>, &'b str) {
-        let mut p = Or::new();
-        p.add_parser(StringValue::new());
-        p.add_parser(AnyChar::new(|ch: char| ch.is_ascii_digit() || ch == '.'));
-        p.add_parser(Object::new(self.mapper, self.level));
-        p.add_parser(Array::new(self.mapper, self.level));
-        p.add_parser(SpecialValue::new());
+impl<'a> Parser<'a, JsonMember<'a>> for KeyAndValue<'_> {
+    fn parse(&self, in_string: &'a str) -> ParseResult<'a, JsonMember<'a>> {
+        let start = in_string.trim_start();
+        let (key, rest) = match self.value.key.parse(start) {
+            ParseResult(Ok(key), rest, _) => (key, rest.trim_start()),
+            ParseResult(Err(e), _, _) => return ParseResult::err(e, in_string),
+        };
+
+        let Some(rest) = rest.strip_prefix(':') else {
+            return ParseResult::err(ParseError::new(rest, "`:`"), in_string);
+        };
+        if let Some(error) = self.value.lone_surrogate(key, start) {
+            return ParseResult::err(error, in_string);
+        }
+        let mut keys = self.keys.take();
+        let duplicate = self.value.duplicate(&mut keys, key, start);
+        self.keys.set(keys);
+        if let Some(error) = duplicate {
+            return ParseResult::err(error, in_string);
+        }
 
-        p.parse(in_string)
+        let after_space = rest.trim_start();
+        match self.value.parse(after_space) {
+            ParseResult(Ok(value), rest_after, furthest) => {
+                let member = JsonMember {
+                    key: Cow::Borrowed(key),
+                    space: Cow::Borrowed(consumed(rest, after_space)),
+                    value,
+                };
+                with_comma(member, rest_after, furthest, self.value.strict.then_some('}'))
+            }
+            ParseResult(Err(e), _, _) => ParseResult::err(e, in_string),
+        }
     }
 }
 
-// поле со скалярным значением, не массив и не объект
-struct KeyAndValue<'a, M> {
-    mapper: &'a M,
-    level: usize,
+// элемент массива вместе с необязательной запятой после него
+struct ValueAndComma<'v> {
+    value: &'v Value,
 }
 
-impl<'a, M> KeyAndValue<'a, M> {
-    fn new(mapper: &'a M, level: usize) -> Self {
-        Self { level, mapper }
+impl<'v> ValueAndComma<'v> {
+    fn new(value: &'v Value) -> Self {
+        Self { value }
     }
 }
 
-impl<'a, M> Parser for KeyAndValue<'a, M>
-where
-    M: for<'c> Fn(&'c str, usize) -> String + Clone,
-{
-    fn parse<'b>(&self, in_string: &'b str) -> (Result<String, ()>, &'b str) {
-        let mut p = And::new();
-        p.add_parser(Ignore::new(ZeroOrMore::new(AnyChar::new(|ch: char| {
-            ch.is_whitespace()
-        }))));
-        p.add_parser(Key::new());
-        p.add_parser(Ignore::new(ZeroOrMore::new(AnyChar::new(|ch: char| {
-            ch.is_whitespace()
-        }))));
-        p.add_parser(CharSequence::new(String::from(":")));
-        p.add_parser(ZeroOrMore::new(AnyChar::new(|ch: char| ch.is_whitespace())));
-        p.add_parser(Value::new(self.mapper, self.level));
-        p.add_parser(Ignore::new(ZeroOrMore::new(AnyChar::new(|ch: char| {
-            ch.is_whitespace()
-        }))));
-        p.add_parser(ZeroOrOne::new(CharSequence::new(String::from(","))));
-
-        let res = p.parse(in_string);
-        if let Ok(r) = res.0 {
-            let r1 = (self.mapper)(&r, self.level);
-            (Ok(r1), res.1)
-        } else {
-            res
+impl<'a> Parser<'a, JsonValue<'a>> for ValueAndComma<'_> {
+    fn parse(&self, in_string: &'a str) -> ParseResult<'a, JsonValue<'a>> {
+        match self.value.parse(in_string.trim_start()) {
+            ParseResult(Ok(value), rest, furthest) => {
+                with_comma(value, rest, furthest, self.value.strict.then_some(']'))
+            }
+            ParseResult(Err(e), _, _) => ParseResult::err(e, in_string),
         }
     }
 }
 
-struct Object<'a, M> {
-    mapper: &'a M,
-    level: usize,
+// пропуск пробелов и запятой после элемента, запятая не обязательна. Со
+// strict_close, скобкой объемлющего массива или объекта, вход проверяется по
+// RFC 8259: запятая нужна перед каждым следующим элементом и не допускается
+// перед скобкой
+fn with_comma<'a, T>(
+    parsed: T,
+    in_string: &'a str,
+    furthest: Option<ParseError<'a>>,
+    strict_close: Option<char>,
+) -> ParseResult<'a, T> {
+    let rest = in_string.trim_start();
+    match (rest.strip_prefix(','), strict_close) {
+        (Some(after), Some(close)) if after.trim_start().starts_with(close) => {
+            let after = after.trim_start();
+            let expected = if close == ']' { "value" } else { "key" };
+            ParseResult::err(ParseError::new(after, expected).furthest(furthest), in_string)
+        }
+        (Some(rest), _) => ParseResult(Ok(parsed), rest, furthest),
+        (None, Some(close)) if !rest.starts_with(close) => {
+            let comma = ParseError::new(rest, "`,`");
+            let error = ParseError::new(rest, format!("`{}`", close)).furthest(Some(comma));
+            ParseResult::err(error.furthest(furthest), in_string)
+        }
+        (None, _) => {
+            let comma = ParseError::new(rest, "`,`").furthest(furthest);
+            ParseResult(Ok(parsed), rest, Some(comma))
+        }
+    }
+}
+
+// элементы между open и close, разобранные item, до первой неудачи
+fn items<'a, T>(
+    in_string: &'a str,
+    item: &impl Parser<'a, T>,
+    open: char,
+    close: char,
+) -> ParseResult<'a, Vec<T>> {
+    let Some(mut rest) = in_string.strip_prefix(open) else {
+        let error = ParseError::new(in_string, format!("`{}`", open));
+        return ParseResult::err(error, in_string);
+    };
+
+    let mut res = Vec::new();
+    let mut furthest = None;
+    let stop = loop {
+        match item.parse(rest) {
+            ParseResult(Ok(parsed), r, f) => {
+                res.push(parsed);
+                rest = r;
+                furthest = furthest_of(f, furthest);
+            }
+            ParseResult(Err(e), _, _) => break e.furthest(furthest),
+        }
+    };
+
+    let rest = rest.trim_start();
+    match rest.strip_prefix(close) {
+        Some(rest) => ParseResult(Ok(res), rest, Some(stop)),
+        None => {
+            let error = ParseError::new(rest, format!("`{}`", close)).furthest(Some(stop));
+            ParseResult::err(error, in_string)
+        }
+    }
+}
+
+struct Object<'v> {
+    value: &'v Value,
 }
 
-impl<'a, M> Object<'a, M> {
-    fn new(mapper: &'a M, level: usize) -> Self {
-        Self { mapper, level }
+impl<'v> Object<'v> {
+    fn new(value: &'v Value) -> Self {
+        Self { value }
     }
 }
 
-impl<'a, M> Parser for Object<'a, M>
-where
-    M: for<'c> Fn(&'c str, usize) -> String + Clone,
-{
-    fn parse<'b>(&self, in_string: &'b str) -> (Result<String, ()>, &'b str) {
-        let mut p = And::new();
-        p.add_parser(CharSequence::new(String::from("{")));
-        p.add_parser(Ignore::new(ZeroOrMore::new(AnyChar::new(|ch: char| {
-            ch.is_whitespace()
-        }))));
-        p.add_parser(ObjectContent::new(self.mapper, self.level + 1));
-        p.add_parser(Ignore::new(ZeroOrMore::new(AnyChar::new(|ch: char| {
-            ch.is_whitespace()
-        }))));
-        p.add_parser(CharSequence::new(String::from("}")));
-
-        let res = p.parse(in_string);
-        return if let Ok(r) = res.0 {
-            let res_len = r.len();
-            let r1 = if res_len > 2 {
-                let last_str = &(self.mapper)("}", self.level);
-                String::from(&r[0..res_len - 1]) + &last_str[0..last_str.len() - 1]
-            } else {
-                r
-            };
-            (Ok(r1), res.1)
-        } else {
-            res
-        };
+impl<'a> Parser<'a, JsonValue<'a>> for Object<'_> {
+    fn parse(&self, in_string: &'a str) -> ParseResult<'a, JsonValue<'a>> {
+        items(in_string, &KeyAndValue::new(self.value), '{', '}').map(JsonValue::Object)
     }
 }
 
-struct ObjectContent<'a, M> {
-    mapper: &'a M,
-    level: usize,
+struct Array<'v> {
+    value: &'v Value,
 }
 
-// список полей
-impl<'a, M> ObjectContent<'a, M> {
-    fn new(mapper: &'a M, level: usize) -> Self {
-        Self { mapper, level }
+impl<'v> Array<'v> {
+    fn new(value: &'v Value) -> Self {
+        Self { value }
     }
 }
 
-impl<'a, M> Parser for ObjectContent<'a, M>
-where
-    M: for<'c> Fn(&'c str, usize) -> String + Clone,
-{
-    fn parse<'b>(&self, in_string: &'b str) -> (Result<String, ()>, &'b str) {
-        let mut p = And::new();
-        p.add_parser(Ignore::new(ZeroOrMore::new(AnyChar::new(|ch: char| {
-            ch.is_whitespace()
-        }))));
-        p.add_parser(ZeroOrMore::new(KeyAndValue::new(self.mapper, self.level)));
-        p.add_parser(Ignore::new(ZeroOrMore::new(AnyChar::new(|ch: char| {
-            ch.is_whitespace()
-        }))));
-
-        let res = p.parse(in_string);
-        return if let Ok(r) = res.0 {
-            let res_len = r.len();
-            let r1 = if res_len != 0 {
-                String::from("\n") + &r
-            } else {
-                r
-            };
-            (Ok(r1), res.1)
-        } else {
-            res
-        };
+impl<'a> Parser<'a, JsonValue<'a>> for Array<'_> {
+    fn parse(&self, in_string: &'a str) -> ParseResult<'a, JsonValue<'a>> {
+        items(in_string, &ValueAndComma::new(self.value), '[', ']').map(JsonValue::Array)
     }
 }
 
-struct ValueAndComma<'a, M> {
-    mapper: &'a M,
-    level: usize,
+// документ: значение, вокруг которого допустимы пробелы
+struct Document {
+    value: Value,
 }
 
-// список полей
-impl<'a, M> ValueAndComma<'a, M> {
-    fn new(mapper: &'a M, level: usize) -> Self {
-        Self { mapper, level }
+impl Document {
+    fn new(strict: bool, max_depth: usize) -> Self {
+        Self {
+            value: Value::new(strict, max_depth),
+        }
     }
 }
 
-impl<'a, M> Parser for ValueAndComma<'a, M>
-where
-    M: (for<'c> Fn(&'c str, usize) -> String) + Clone,
-{
-    fn parse<'b>(&self, in_string: &'b str) -> (Result<String, ()>, &'b str) {
-        let mut p = And::new();
-        p.add_parser(Ignore::new(ZeroOrMore::new(AnyChar::new(|ch: char| {
-            ch.is_whitespace()
-        }))));
-        p.add_parser(Value::new(self.mapper, self.level));
-        p.add_parser(Ignore::new(ZeroOrMore::new(AnyChar::new(|ch: char| {
-            ch.is_whitespace()
-        }))));
-        p.add_parser(ZeroOrOne::new(CharSequence::new(String::from(","))));
-
-        let res = p.parse(in_string);
-        if let Ok(r) = res.0 {
-            (Ok((self.mapper)(&r, self.level)), res.1)
-        } else {
-            res
+impl<'a> Parser<'a, JsonValue<'a>> for Document {
+    fn parse(&self, in_string: &'a str) -> ParseResult<'a, JsonValue<'a>> {
+        match self.value.parse(in_string.trim_start()) {
+            ParseResult(Ok(value), rest, furthest) => {
+                ParseResult(Ok(value), rest.trim_start(), furthest)
+            }
+            ParseResult(Err(e), _, _) => ParseResult::err(e, in_string),
         }
     }
 }
 
-struct ArrayContent<'a, M> {
-    mapper: &'a M,
-    level: usize,
+// значение, начинающееся на текущей строке; вложенные строки с отступом level
+fn print_value(value: &JsonValue, level: usize, options: &FormatOptions) -> String {
+    let mut res = String::new();
+    write_value(&mut res, value, level, options);
+    res
 }
 
-// список полей
-impl<'a, M> ArrayContent<'a, M> {
-    fn new(mapper: &'a M, level: usize) -> Self {
-        Self { mapper, level }
+// то же с выводом в конец res
+fn write_value(res: &mut String, value: &JsonValue, level: usize, options: &FormatOptions) {
+    let json = options.json;
+    match value {
+        JsonValue::String(raw) => res.push_str(&json.escape.apply(raw)),
+        // число в записи ECMAScript, переполнение проверено при разборе
+        JsonValue::Number(number) if json.normalize => match number.parse::<f64>() {
+            Ok(value) if value.is_finite() => res.push_str(&ecmascript_number(value)),
+            _ => res.push_str(number),
+        },
+        JsonValue::Number(number) => res.push_str(number),
+        JsonValue::Literal(literal) if json.normalize => {
+            res.push_str(&literal.to_ascii_lowercase())
+        }
+        JsonValue::Literal(literal) => res.push_str(literal),
+        JsonValue::Array(values) => {
+            let values: Vec<&JsonValue> = values.iter().collect();
+            write_block(res, &values, "[", "]", level, options, |res, value| {
+                write_value(res, value, level + 1, options)
+            });
+        }
+        JsonValue::Object(members) => {
+            // сортировка устойчива, поэтому одинаковые ключи остаются в порядке входа
+            let mut members: Vec<&JsonMember> = members.iter().collect();
+            if json.sort_keys != KeyOrder::Input {
+                let mut decoded: Vec<(String, &JsonMember)> =
+                    members.iter().map(|member| (decode(&member.key), *member)).collect();
+                decoded.sort_by(|(a, _), (b, _)| json.sort_keys.compare(a, b));
+                members = decoded.into_iter().map(|(_, member)| member).collect();
+            }
+
+            write_block(res, &members, "{", "}", level, options, |res, member| {
+                res.push_str(&json.escape.apply(&member.key));
+                res.push(':');
+                if !options.minify {
+                    res.push_str(&member.space);
+                }
+                write_value(res, &member.value, level + 1, options);
+            });
+        }
     }
 }
 
-impl<'a, M> Parser for ArrayContent<'a, M>
-where
-    M: for<'c> Fn(&'c str, usize) -> String + Clone,
-{
-    fn parse<'b>(&self, in_string: &'b str) -> (Result<String, ()>, &'b str) {
-        let mut p = And::new();
-        p.add_parser(ZeroOrMore::new(ValueAndComma::new(self.mapper, self.level)));
-
-        let res = p.parse(in_string);
-        if let Ok(r) = res.0 {
-            let res_len = r.len();
-            let r1 = if res_len != 0 {
-                String::from("\n") + &r
-            } else {
-                r
-            };
-            (Ok(r1), res.1)
-        } else {
-            res
+// элементы массива или поля объекта, каждое на своей строке и через запятую
+fn write_block<T>(
+    res: &mut String,
+    items: &[T],
+    open: &str,
+    close: &str,
+    level: usize,
+    options: &FormatOptions,
+    write_item: impl Fn(&mut String, &T),
+) {
+    res.push_str(open);
+    if items.is_empty() {
+        res.push_str(close);
+        return;
+    }
+
+    res.push_str(options.newline());
+    for (i, item) in items.iter().enumerate() {
+        options.indent_line(res, level + 1);
+        write_item(res, item);
+        if i + 1 < items.len() {
+            res.push(',');
         }
+        res.push_str(options.newline());
     }
+
+    options.indent_line(res, level);
+    res.push_str(close);
 }
 
-struct Array<'a, M> {
-    mapper: &'a M,
-    level: usize,
+// разбор json по частям: лексемы разбираются теми же парсерами, что и в
+// parse, а открытые объекты и массивы хранятся в стеке, поэтому в памяти
+// остаются только неразобранная лексема и стек. Сортировка полей требует
+// всего объекта, и с ней документ разбирается целиком в конце входа
+pub struct JsonStream {
+    // грамматика строится один раз на поток, None только внутри advance
+    document: Option<Document>,
+    // открытые объекты и массивы: true - объект, и число элементов в них
+    stack: Vec<(bool, usize)>,
+    // ключи открытых объектов для проверки повторов при normalize, по одному
+    // набору на каждый элемент stack
+    keys: Vec<HashSet<String>>,
+    state: JsonState,
+    // после предыдущего элемента не было запятой, она попадёт в ошибку
+    comma_missing: bool,
+    // значение поля ещё не началось: пробелы перед ним выводятся как во входе
+    after_colon: bool,
 }
 
-impl<'a, M> Array<'a, M> {
-    fn new(mapper: &'a M, level: usize) -> Self {
-        Self { mapper, level }
-    }
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum JsonState {
+    // значение документа или поля после `:`
+    Value,
+    // элемент массива, поле объекта или закрывающая скобка
+    Item,
+    // необязательная запятая после элемента
+    Comma,
+    // значение документа разобрано, дальше только пробелы
+    Done,
 }
 
-impl<'a, M> Parser for Array<'a, M>
-where
-    M: for<'c> Fn(&'c str, usize) -> String + Clone,
-{
-    fn parse<'b>(&self, in_string: &'b str) -> (Result<String, ()>, &'b str) {
-        let mut p = And::new();
-        p.add_parser(CharSequence::new(String::from("[")));
-        p.add_parser(Ignore::new(ZeroOrMore::new(AnyChar::new(|ch: char| {
-            ch.is_whitespace()
-        }))));
-        p.add_parser(ArrayContent::new(self.mapper, self.level + 1));
-        p.add_parser(Ignore::new(ZeroOrMore::new(AnyChar::new(|ch: char| {
-            ch.is_whitespace()
-        }))));
-
-        p.add_parser(CharSequence::new(String::from("]")));
-
-        let res = p.parse(in_string);
-        return if let Ok(r) = res.0 {
-            let res_len = r.len();
-            let r1 = if res_len > 2 {
-                let last_str = &(self.mapper)("]", self.level);
-                String::from(&r[0..res_len - 1]) + &last_str[0..last_str.len() - 1]
-            } else {
-                r
+// продолжение разбора после шага
+enum Step<'a> {
+    // разобрано столько байт, и самая дальняя ошибка внутри лексемы
+    Consumed(usize, Option<ParseError<'a>>),
+    // лексема не поместилась в разобранный вход
+    More,
+}
+
+impl JsonStream {
+    // значение после prefix: открывающая скобка или значение целиком
+    fn value<'a>(
+        &mut self,
+        value: &Value,
+        rest: &'a str,
+        end: bool,
+        prefix: &str,
+        options: &FormatOptions,
+        out: &mut String,
+    ) -> Result<Step<'a>, ParseError<'a>> {
+        if let Some(open) = rest.chars().next().filter(|ch| *ch == '{' || *ch == '[') {
+            if self.stack.len() >= options.max_depth {
+                return Err(ParseError::too_deep(rest, options.max_depth));
+            }
+            out.push_str(prefix);
+            out.push(open);
+            self.stack.push((open == '{', 0));
+            self.keys.push(HashSet::new());
+            self.state = JsonState::Item;
+            self.after_colon = false;
+            return Ok(Step::Consumed(1, None));
+        }
+
+        match value.parse(rest) {
+            // число может продолжиться в следующей части входа
+            ParseResult(Ok(_), r, _) if r.len() < LOOKAHEAD && !end => Ok(Step::More),
+            ParseResult(Ok(parsed), r, furthest) => {
+                out.push_str(prefix);
+                write_value(out, &parsed, 0, options);
+                self.after_colon = false;
+                self.close_value();
+                Ok(Step::Consumed(rest.len() - r.len(), furthest))
+            }
+            ParseResult(Err(e), _, _) => Err(e),
+        }
+    }
+
+    // значение закончилось: дальше запятая в объемлющем объекте или массиве
+    fn close_value(&mut self) {
+        self.state = match self.stack.last_mut() {
+            Some((_, items)) => {
+                *items += 1;
+                JsonState::Comma
+            }
+            None => JsonState::Done,
+        };
+    }
+
+    // элемент или поле открытого объекта или массива, либо его конец
+    fn item<'a>(
+        &mut self,
+        value: &Value,
+        rest: &'a str,
+        end: bool,
+        options: &FormatOptions,
+        out: &mut String,
+    ) -> Result<Step<'a>, ParseError<'a>> {
+        let (object, items) = self.stack[self.stack.len() - 1];
+        let level = self.stack.len();
+        let close = if object { "}" } else { "]" };
+        // как в with_comma со strict_close
+        if options.json.normalize && items > 0 {
+            match (self.comma_missing, rest.starts_with(close)) {
+                (false, true) => {
+                    return Err(ParseError::new(rest, if object { "key" } else { "value" }));
+                }
+                (true, false) => {
+                    let comma = ParseError::new(rest, "`,`");
+                    return Err(ParseError::new(rest, format!("`{}`", close)).furthest(Some(comma)));
+                }
+                _ => {}
+            }
+        }
+        if rest.starts_with(close) {
+            if items > 0 {
+                out.push_str(options.newline());
+                options.indent_line(out, level - 1);
+            }
+            out.push_str(close);
+            self.stack.pop();
+            self.keys.pop();
+            self.close_value();
+            return Ok(Step::Consumed(1, None));
+        }
+
+        let mut prefix = String::from(if items > 0 { "," } else { "" });
+        prefix.push_str(options.newline());
+        options.indent_line(&mut prefix, level);
+
+        // ошибка элемента объединяется с ожиданием запятой и скобки, как в items
+        let stop = match self.comma_missing {
+            true => Some(ParseError::new(rest, "`,`")),
+            false => None,
+        };
+        let error = |e: ParseError<'a>| {
+            let close = ParseError::new(rest, format!("`{}`", close));
+            close.furthest(Some(e.furthest(stop)))
+        };
+
+        if !object {
+            return self.value(value, rest, end, &prefix, options, out).map_err(error);
+        }
+
+        let (key, after_key) = match value.key.parse(rest) {
+            ParseResult(Ok(key), r, _) => (key, r.trim_start()),
+            ParseResult(Err(e), _, _) => return Err(error(e)),
+        };
+        let Some(after_colon) = after_key.strip_prefix(':') else {
+            return match after_key.is_empty() && !end {
+                true => Ok(Step::More),
+                false => Err(ParseError::new(after_key, "`:`")),
             };
-            (Ok(r1), res.1)
-        } else {
-            res
         };
+        // как в KeyAndValue
+        if let Some(e) = value.lone_surrogate(key, rest) {
+            return Err(error(e));
+        }
+        if let Some(e) = value.duplicate(&mut self.keys[level - 1], key, rest) {
+            return Err(error(e));
+        }
+
+        out.push_str(&prefix);
+        out.push_str(&options.json.escape.apply(key));
+        out.push(':');
+        self.state = JsonState::Value;
+        self.after_colon = true;
+        Ok(Step::Consumed(rest.len() - after_colon.len(), None))
+    }
+
+    // шаг разбора грамматикой document
+    fn run(
+        &mut self,
+        document: &Document,
+        input: &str,
+        end: bool,
+        options: &FormatOptions,
+        out: &mut String,
+    ) -> Result<usize, FormatError> {
+        if options.json.sort_keys != KeyOrder::Input {
+            if !end {
+                return Ok(0);
+            }
+            let value = parse_document(document, input)?;
+            write_value(out, &value, 0, options);
+            return Ok(input.len());
+        }
+
+        let mut rest = input;
+        // самая дальняя ошибка внутри разобранных лексем, как третье поле ParseResult
+        let mut furthest: Option<ParseError> = None;
+        loop {
+            let trimmed = rest.trim_start();
+            // как при печати дерева
+            if self.after_colon && !options.minify {
+                out.push_str(consumed(rest, trimmed));
+            }
+            rest = trimmed;
+            if !end && rest.len() < LOOKAHEAD {
+                break;
+            }
+
+            let step = match self.state {
+                JsonState::Done if rest.is_empty() => break,
+                JsonState::Done => {
+                    return Err(match furthest {
+                        Some(e) if e.rest.len() < rest.len() => FormatError::syntax(input, &e),
+                        _ => FormatError::trailing(input, rest),
+                    })
+                }
+                JsonState::Value => self.value(&document.value, rest, end, "", options, out),
+                JsonState::Item => self.item(&document.value, rest, end, options, out),
+                JsonState::Comma => {
+                    self.state = JsonState::Item;
+                    self.comma_missing = !rest.starts_with(',');
+                    Ok(Step::Consumed(if self.comma_missing { 0 } else { 1 }, None))
+                }
+            };
+
+            match step.map_err(|e| e.furthest(furthest.clone())) {
+                Ok(Step::Consumed(len, e)) => {
+                    furthest = furthest_of(e, furthest);
+                    rest = &rest[len..];
+                }
+                Ok(Step::More) => break,
+                // ошибка у конца входа - лексема ещё не пришла целиком
+                Err(e) if e.rest.len() < LOOKAHEAD && e.too_deep.is_none() && !end => break,
+                Err(e) => return Err(FormatError::syntax(input, &e)),
+            }
+        }
+
+        Ok(input.len() - rest.len())
+    }
+}
+
+impl Machine for JsonStream {
+    fn new(options: &FormatOptions) -> Self {
+        Self {
+            document: Some(Document::new(options.json.normalize, options.max_depth)),
+            stack: Vec::new(),
+            keys: Vec::new(),
+            state: JsonState::Value,
+            comma_missing: false,
+            after_colon: false,
+        }
     }
+
+    fn advance(
+        &mut self,
+        input: &str,
+        end: bool,
+        options: &FormatOptions,
+        out: &mut String,
+    ) -> Result<usize, FormatError> {
+        // грамматика на время шага вынимается, чтобы разбирать ею, пока
+        // меняется состояние разбора
+        let document = self.document.take().expect("grammar is put back after each step");
+        let res = self.run(&document, input, end, options, out);
+        self.document = Some(document);
+        res
+    }
+}
+
+// форматирование из reader в writer по частям, без чтения всего входа в память
+pub fn format_stream<R: Read, W: Write>(
+    reader: R,
+    writer: W,
+    options: impl Into<FormatOptions>,
+) -> Result<(), FormatError> {
+    stream::copy(Stream::<JsonStream>::new(options.into()), reader, writer)
 }
 
+// форматирование json, который приходит частями
+pub type JsonFormatter = Formatter<JsonStream>;
+
 #[cfg(test)]
 mod tests {
     use super::{
-        ArrayContent, Key, KeyAndValue, ObjectContent, Value, ValueAndComma, Parser
+        canonicalize, canonicalize_with, format, format_lenient, parse, parse_with, print,
+        print_value, Array, EscapeMode, JsonMember, JsonOptions, JsonString, JsonValue, Key,
+        KeyAndValue, KeyOrder, Number, Object, Parser, Value, ValueAndComma,
     };
+    use crate::{ErrorKind, FormatOptions, DEFAULT_MAX_DEPTH};
+
+    fn escaped(escape: EscapeMode) -> FormatOptions {
+        let json = JsonOptions {
+            escape,
+            ..JsonOptions::default()
+        };
+        FormatOptions::new().indent(2).json(json)
+    }
 
     #[test]
     fn key() {
         let p = Key::new();
 
         let input = "\"aaa\": 234234";
-        let res = p.parse(&input);
+        let res = p.parse(input);
         assert_eq!("\"aaa\"", res.0.unwrap());
         assert_eq!(": 234234", res.1);
 
-        let input = "\"aaa+32\": 234234";
-        let res = p.parse(&input);
-        assert_eq!(false, res.0.is_ok());
-        assert_eq!("\"aaa+32\": 234234", res.1);
+        let input = "\"aaa+32 $x.y\": 234234";
+        let res = p.parse(input);
+        assert_eq!("\"aaa+32 $x.y\"", res.0.unwrap());
+        assert_eq!(": 234234", res.1);
+
+        let input = "\"a\\\"b\\u00e9\": 1";
+        let res = p.parse(input);
+        assert_eq!("\"a\\\"b\\u00e9\"", res.0.unwrap());
+
+        let input = "aaa: 234234";
+        let res = p.parse(input);
+        assert!(res.0.is_err());
+        assert_eq!("aaa: 234234", res.1);
+    }
+
+    #[test]
+    fn number() {
+        let p = Number::new();
+
+        for input in ["0", "-0", "12345", "-1.25", "1e-7", "6.02E+23", "0.000100", "1E5"] {
+            let res = p.parse(input);
+            assert_eq!(input, res.0.unwrap());
+            assert_eq!("", res.1);
+        }
+
+        assert!(p.parse("1.2.3").0.is_err());
+
+        for (input, offset) in [("0123", 1), ("-01", 2), ("00.5", 1)] {
+            let err = p.parse(input).0.unwrap_err();
+            assert_eq!(offset, err.offset(input));
+        }
+        let err = format("[01]", 4).unwrap_err();
+        assert_eq!(ErrorKind::Syntax, err.kind());
+        assert_eq!(2, err.offset());
+        assert!(format("[-01, 00.5]", 4).is_err());
+
+        assert!(p.parse("-").0.is_err());
+        assert!(p.parse(".5").0.is_err());
+        assert!(p.parse("+1").0.is_err());
+
+        let err = format("[1.]", 4).unwrap_err();
+        assert_eq!(3, err.offset());
+        assert_eq!(Some("digit"), err.expected());
+
+        let err = format("[1e+]", 4).unwrap_err();
+        assert_eq!(4, err.offset());
+
+        let err = format("[1.2.3]", 4).unwrap_err();
+        assert_eq!(4, err.offset());
+
+        let res = format("{\"fare\": -1250.00, \"rate\": 1.0e-7}", 2);
+        assert_eq!("{\n  \"fare\": -1250.00,\n  \"rate\": 1.0e-7\n}", res.unwrap());
+    }
+
+    #[test]
+    fn json_string() {
+        let p = JsonString::new();
+
+        let input = r#""say \"hi\" \\ \/ \b\f\n\r\t \u041c" tail"#;
+        let res = p.parse(input);
+        assert_eq!(r#""say \"hi\" \\ \/ \b\f\n\r\t \u041c""#, res.0.unwrap());
+        assert_eq!(" tail", res.1);
+
+        let res = p.parse(r#""""#);
+        assert_eq!(r#""""#, res.0.unwrap());
+
+        let input = r#""bad \x escape""#;
+        let err = p.parse(input).0.unwrap_err();
+        assert_eq!(6, err.offset(input));
+        assert_eq!(Some("escape sequence".to_string()), err.describe());
+
+        let input = r#""\u12g4""#;
+        let err = p.parse(input).0.unwrap_err();
+        assert_eq!(5, err.offset(input));
+        assert_eq!(Some("hex digit".to_string()), err.describe());
+
+        let input = "\"line\nbreak\"";
+        let err = p.parse(input).0.unwrap_err();
+        assert_eq!(5, err.offset(input));
+
+        let input = r#""unterminated \""#;
+        let err = p.parse(input).0.unwrap_err();
+        assert_eq!(input.len(), err.offset(input));
+    }
+
+    #[test]
+    fn surrogate_strings() {
+        let res = format(r#"["\ud83d\ude00", "\ud83d", "\u0022"]"#, 2);
+        assert_eq!("[\n  \"😀\",\n  \"\\ud83d\",\n  \"\\u0022\"\n]", res.unwrap());
+    }
+
+    #[test]
+    fn escape_modes() {
+        let input = r#"{"\u0438\u043c\u044f": "Мария\n\"Ivanova\" \ud83d\ude00\/\u0022"}"#;
+
+        let res = format(input, escaped(EscapeMode::Preserve)).unwrap();
+        assert_eq!(format!("{{\n  {}\n}}", &input[1..input.len() - 1]), res);
+
+        let res = format(input, escaped(EscapeMode::DecodeUnicode)).unwrap();
+        assert_eq!("{\n  \"имя\": \"Мария\\n\\\"Ivanova\\\" 😀\\/\\u0022\"\n}", res);
+
+        let res = format(input, escaped(EscapeMode::DecodeAll)).unwrap();
+        assert_eq!("{\n  \"имя\": \"Мария\n\"Ivanova\" 😀/\"\"\n}", res);
+
+        let res = format(input, escaped(EscapeMode::AsciiOnly)).unwrap();
+        assert_eq!(
+            r#"{
+  "\u0438\u043c\u044f": "\u041c\u0430\u0440\u0438\u044f\n\"Ivanova\" \ud83d\ude00\/\u0022"
+}"#,
+            res
+        );
+        assert!(res.is_ascii());
+    }
+
+    #[test]
+    fn escaped_strings() {
+        let res = format(r#"{"say": "\"hi\"", "a.b c": ["\\", "\u041c\u0430"]}"#, 2);
+        assert_eq!(
+            "{\n  \"say\": \"\\\"hi\\\"\",\n  \"a.b c\": [\n    \"\\\\\",\n    \"Ма\"\n  ]\n}",
+            res.unwrap()
+        );
     }
 
     #[test]
     fn value_and_comma() {
-        let mapper = move |parsed: &str, level: usize| " ".repeat(4 * level) + parsed + "\n";
-        let p = ValueAndComma::new(&mapper, 1);
+        let value = Value::new(false, DEFAULT_MAX_DEPTH);
+        let p = ValueAndComma::new(&value);
 
         let input = "\"aaaa\",";
-        let res = p.parse(&input);
-        assert_eq!("    \"aaaa\",\n", res.0.unwrap());
+        let res = p.parse(input);
+        assert_eq!(JsonValue::String("\"aaaa\"".into()), res.0.unwrap());
         assert_eq!("", res.1);
 
         let input = "        \t\t\"aaaa\"   ,";
-        let res = p.parse(&input);
-        assert_eq!("    \"aaaa\",\n", res.0.unwrap());
+        let res = p.parse(input);
+        assert_eq!(JsonValue::String("\"aaaa\"".into()), res.0.unwrap());
         assert_eq!("", res.1);
     }
 
     #[test]
     fn value() {
-        let mapper = move |parsed: &str, level: usize| " ".repeat(4 * level) + parsed + "\n";
-        let p = Value::new(&mapper, 0);
+        let p = Value::new(false, DEFAULT_MAX_DEPTH);
+        let printed = |input| {
+            let res = p.parse(input);
+            (print_value(&res.0.unwrap(), 0, &FormatOptions::default()), res.1)
+        };
 
         let input = "\"aklsdkj33+++390  sldk sdf sdf ''\"";
-        let res = p.parse(&input);
-        assert_eq!(input, res.0.unwrap());
-        assert_eq!("", res.1);
-
-        let input = "12345";
-        let res = p.parse(&input);
-        assert_eq!(input, res.0.unwrap());
-        assert_eq!("", res.1);
-
-        let input = "234  ";
-        let res = p.parse(&input);
-        assert_eq!("234", res.0.unwrap());
-        assert_eq!("  ", res.1);
-
-        let input = "{}";
-        let res = p.parse(&input);
-        assert_eq!("{}", res.0.unwrap());
-        assert_eq!("", res.1);
-
-        let input = "[]";
-        let res = p.parse(&input);
-        assert_eq!("[]", res.0.unwrap());
-        assert_eq!("", res.1);
+        assert_eq!((input.to_string(), ""), printed(input));
+        assert_eq!((String::from("12345"), ""), printed("12345"));
+        assert_eq!((String::from("234"), "  "), printed("234  "));
+        assert_eq!((String::from("{}"), ""), printed("{}"));
+        assert_eq!((String::from("[]"), ""), printed("[]"));
 
-        let input = "{\r\n\"aaa\": \"bbb\"\r\n}";
-        let res = p.parse(&input);
-        assert_eq!("{\n    \"aaa\": \"bbb\"\n}", res.0.unwrap());
-        assert_eq!("", res.1);
+        let res = printed("{\r\n\"aaa\": \"bbb\"\r\n}");
+        assert_eq!("{\n    \"aaa\": \"bbb\"\n}", res.0);
 
-        let input = "[1, 2, 3, {\"aaa\": 1}]";
-        let res = p.parse(&input);
+        let res = printed("[1, 2, 3, {\"aaa\": 1}]");
         assert_eq!(
             "[\n    1,\n    2,\n    3,\n    {\n        \"aaa\": 1\n    }\n]",
-            res.0.unwrap()
+            res.0
         );
-        assert_eq!("", res.1);
 
-        let input = "{\"a\": 1, \"b\": 2, \"c\": 3, \"d\": {\"aaa\": 1}}";
-        let res = p.parse(&input);
+        let res = printed("{\"a\": 1, \"b\": 2, \"c\": 3, \"d\": {\"aaa\": 1}}");
         assert_eq!(
             "{\n    \"a\": 1,\n    \"b\": 2,\n    \"c\": 3,\n    \"d\": {\n        \"aaa\": 1\n    }\n}",
-            res.0.unwrap()
+            res.0
         );
-        assert_eq!("", res.1);
 
-        let input = "{\"total\":1,\"errors\":null}";
-        let res = p.parse(&input);
-        assert_eq!(
-            "{\n    \"total\":1,\n    \"errors\":null\n}",
-            res.0.unwrap()
-        );
-        assert_eq!("", res.1);
+        let res = printed("{\"total\":1,\"errors\":null}");
+        assert_eq!("{\n    \"total\":1,\n    \"errors\":null\n}", res.0);
+    }
+
+    fn member<'a>(key: &'a str, space: &'a str, value: JsonValue<'a>) -> JsonMember<'a> {
+        JsonMember {
+            key: key.into(),
+            space: space.into(),
+            value,
+        }
     }
 
     #[test]
     fn full_field_with_value() {
-        let ident = 4;
-        let mapper = move |parsed: &str, level: usize| " ".repeat(ident * level) + parsed + "\n";
-        let p = KeyAndValue::new(&mapper, 1);
+        let value = Value::new(false, DEFAULT_MAX_DEPTH);
+        let p = KeyAndValue::new(&value);
+        let number = |n: &'static str| JsonValue::Number(n.into());
 
         let input = "\"key\"   : \"value\"";
-        let res = p.parse(&input);
-        assert_eq!("    \"key\": \"value\"\n", res.0.unwrap());
+        let res = p.parse(input);
+        let value = JsonValue::String("\"value\"".into());
+        assert_eq!(member("\"key\"", " ", value.clone()), res.0.unwrap());
         assert_eq!("", res.1);
 
-        let input = "   \"key\"   : \"value\"    ,";
-        let res = p.parse(&input);
-        assert_eq!("    \"key\": \"value\",\n", res.0.unwrap());
-        assert_eq!("", res.1);
-
-        let input = "\"key\" : { \"key2\": 1234 }";
-        let res = p.parse(&input);
-        assert_eq!(
-            "    \"key\": {\n        \"key2\": 1234\n    }\n",
-            res.0.unwrap()
-        );
+        let input = "   \"key\"   :\"value\"    ,";
+        let res = p.parse(input);
+        assert_eq!(member("\"key\"", "", value), res.0.unwrap());
         assert_eq!("", res.1);
 
+        let object = JsonValue::Object(vec![member("\"key2\"", " ", number("1234"))]);
         let input = "\n          \"key\" : { \"key2\": 1234 }";
-        let res = p.parse(&input);
-        assert_eq!(
-            "    \"key\": {\n        \"key2\": 1234\n    }\n",
-            res.0.unwrap()
-        );
+        let res = p.parse(input);
+        assert_eq!(member("\"key\"", " ", object), res.0.unwrap());
         assert_eq!("", res.1);
     }
 
     #[test]
     fn object_content() {
-        let ident = 4;
-        let mapper = move |parsed: &str, level: usize| " ".repeat(ident * level) + parsed + "\n";
-        let p = ObjectContent::new(&mapper, 1);
-
-        let input = " \"key\" : \"value\" ";
-        let res = p.parse(&input);
-        assert_eq!("\n    \"key\": \"value\"\n", res.0.unwrap());
+        let value = Value::new(false, DEFAULT_MAX_DEPTH);
+        let p = Object::new(&value);
+
+        let input = "{ \"key\" : \"value\" ,    \"key2\" : 2}";
+        let res = p.parse(input);
+        let expected = JsonValue::Object(vec![
+            member("\"key\"", " ", JsonValue::String("\"value\"".into())),
+            member("\"key2\"", " ", JsonValue::Number("2".into())),
+        ]);
+        assert_eq!(expected, res.0.unwrap());
         assert_eq!("", res.1);
 
-        let input = " \"key\" : \"value\" ,    \"key2\" : \"value2\"";
-        let res = p.parse(&input);
-        assert_eq!(
-            "\n    \"key\": \"value\",\n    \"key2\": \"value2\"\n",
-            res.0.unwrap()
-        );
-        assert_eq!("", res.1);
+        let res = print_value(&expected, 1, &FormatOptions::default());
+        assert_eq!("{\n        \"key\": \"value\",\n        \"key2\": 2\n    }", res);
     }
 
     #[test]
     fn array_content() {
-        let ident = 4;
-        let mapper = move |parsed: &str, level: usize| " ".repeat(ident * level) + parsed + "\n";
-        let p = ArrayContent::new(&mapper, 1);
+        let value = Value::new(false, DEFAULT_MAX_DEPTH);
+        let p = Array::new(&value);
 
-        let input = "1, 2, 3";
-        let res = p.parse(&input);
-        assert_eq!("\n    1,\n    2,\n    3\n", res.0.unwrap());
+        let res = p.parse("[1, 2 3,]");
+        let number = |n: &'static str| JsonValue::Number(n.into());
+        let expected = JsonValue::Array(vec![number("1"), number("2"), number("3")]);
+        assert_eq!(expected, res.0.unwrap());
         assert_eq!("", res.1);
+
+        let res = print_value(&expected, 0, &FormatOptions::default());
+        assert_eq!("[\n    1,\n    2,\n    3\n]", res);
+    }
+
+    #[test]
+    fn tree() {
+        let input = "{\"name\": \"\\u041c\\u0430\", \"list\": [true, null], \"n\": 1.50}";
+        let value = parse(input).unwrap();
+        assert_eq!(Some("Ма".to_string()), value.get("name").and_then(JsonValue::as_str));
+        assert_eq!(Some(&JsonValue::Number("1.50".into())), value.get("n"));
+        assert_eq!(None, value.get("missing"));
+
+        let list = JsonValue::Array(vec![
+            JsonValue::Literal("true".into()),
+            JsonValue::Literal("null".into()),
+        ]);
+        assert_eq!(Some(&list), value.get("list"));
+
+        // дерево можно изменить и вывести без повторного разбора
+        let JsonValue::Object(mut members) = value else {
+            panic!("object expected");
+        };
+        members.retain(|member| member.key != "\"name\"");
+        let res = print(&JsonValue::Object(members), FormatOptions::new().indent(2));
+        assert_eq!("{\n  \"list\": [\n    true,\n    null\n  ],\n  \"n\": 1.50\n}", res);
+
+        let options = FormatOptions::new().minify(true).json(JsonOptions::canonical());
+        assert_eq!("[1,\"a\"]", print(&parse("[1.0, \"\\u0061\"]").unwrap(), options));
+
+        assert!(parse("[1,").is_err());
+    }
+
+    #[test]
+    fn format_errors() {
+        let res = format("  {\"a\": 1}\n", 4);
+        assert_eq!("{\n    \"a\": 1\n}", res.unwrap());
+
+        let err = format("{\"a\": 1} 2", 4).unwrap_err();
+        assert_eq!(ErrorKind::TrailingCharacters, err.kind());
+        assert_eq!(9, err.offset());
+        assert_eq!((1, 10), (err.line(), err.column()));
+
+        let err = format("\n  {\"a\": }", 4).unwrap_err();
+        assert_eq!(ErrorKind::Syntax, err.kind());
+        assert_eq!((2, 9), (err.line(), err.column()));
+        assert_eq!(Some("value"), err.expected());
+
+        let err = format("[1, {\"a\": 1 x}]", 4).unwrap_err();
+        assert_eq!(12, err.offset());
+        assert_eq!(Some("`,`, key or `}`"), err.expected());
+
+        let err = format("{\"a\": [1, 2}", 4).unwrap_err();
+        assert_eq!(11, err.offset());
+        assert_eq!("expected `,`, value or `]` at line 1, column 12", err.to_string());
+
+        let err = format("{\"a\": \"text", 4).unwrap_err();
+        assert_eq!(ErrorKind::UnexpectedEnd, err.kind());
+        assert_eq!(Some("`\"`"), err.expected());
+
+        let err = format("   ", 4).unwrap_err();
+        assert_eq!(ErrorKind::UnexpectedEnd, err.kind());
+        assert_eq!(Some("value"), err.expected());
+    }
+
+    #[test]
+    fn max_depth() {
+        let options = FormatOptions::new().minify(true).max_depth(3);
+        assert_eq!("[{\"a\":[]}]", format("[{\"a\": []}]", options).unwrap());
+
+        let err = format("[{\"a\": [[]]}]", options).unwrap_err();
+        assert_eq!(ErrorKind::TooDeep, err.kind());
+        assert_eq!(8, err.offset());
+        assert_eq!("nesting deeper than 3 levels at line 1, column 9", err.to_string());
+
+        // без ограничения такой документ переполнил бы стек
+        let err = parse(&"[".repeat(100_000)).unwrap_err();
+        assert_eq!(ErrorKind::TooDeep, err.kind());
+        assert_eq!(DEFAULT_MAX_DEPTH, err.offset());
+
+        // ограничение из options и при разборе в дерево, и в канонической форме
+        let body = "[{\"a\": [[]]}]";
+        assert!(parse_with(body, options.max_depth(4)).is_ok());
+        let err = parse_with(body, options).unwrap_err();
+        assert_eq!((ErrorKind::TooDeep, 8), (err.kind(), err.offset()));
+        let err = canonicalize_with(body, options).unwrap_err();
+        assert_eq!((ErrorKind::TooDeep, 8), (err.kind(), err.offset()));
+        let err = crate::canonicalize_with(body, options).unwrap_err();
+        assert_eq!((ErrorKind::TooDeep, 8), (err.kind(), err.offset()));
+        assert!(canonicalize_with(body, options.max_depth(4)).is_ok());
+    }
+
+    #[test]
+    fn lenient_format() {
+        assert_eq!("{\"a\": }", format_lenient("{\"a\": }", 4));
+        assert_eq!("[\n    1\n]", format_lenient("[1]", 4));
+    }
+
+    #[test]
+    fn canonical() {
+        // пример из RFC 8785, раздел 3.2.2
+        let input = r#"{
+  "numbers": [333333333.33333329, 1E30, 4.50, 2e-3, 0.000000000000000000000000001],
+  "string": "\u20ac$\u000F\u000aA'\u0042\u0022\u005c\\\"\/",
+  "literals": [null, true, false]
+}"#;
+        let expected = r#"{"literals":[null,true,false],"numbers":[333333333.3333333,1e+30,4.5,0.002,1e-27],"string":"€$\u000f\nA'B\"\\\\\"/"}"#;
+        assert_eq!(expected, canonicalize(input).unwrap());
+
+        // порядок по utf-16, раздел 3.2.3
+        let input = r#"{"\u20ac": 1, "\r": 2, "\ufb33": 3, "1": 4, "\ud83d\ude00": 5, "\u0080": 6, "\u00f6": 7}"#;
+        let expected = "{\"\\r\":2,\"1\":4,\"\u{80}\":6,\"ö\":7,\"€\":1,\"😀\":5,\"\u{fb33}\":3}";
+        assert_eq!(expected, canonicalize(input).unwrap());
+
+        for (input, expected) in [
+            ("-0", "0"),
+            ("1e21", "1e+21"),
+            ("123e18", "123000000000000000000"),
+            ("0.000001", "0.000001"),
+            ("-1.5e-7", "-1.5e-7"),
+            ("5e-324", "5e-324"),
+            ("1.7976931348623157e308", "1.7976931348623157e+308"),
+            ("9007199254740993", "9007199254740992"),
+            ("[TRUE, NULL]", "[true,null]"),
+        ] {
+            assert_eq!(expected, canonicalize(input).unwrap(), "{}", input);
+        }
+
+        let err = canonicalize("[1, 1e400]").unwrap_err();
+        assert_eq!(Some("finite number or `]`"), err.expected());
+        assert_eq!(4, err.offset());
+
+        // каноническая форма определена только для корректного json
+        for (input, offset, expected) in [
+            ("{\"b\":1 \"a\":2}", 7, "`,` or `}`"),
+            ("[1 2]", 3, "`,` or `]`"),
+            ("[1,]", 3, "value"),
+            ("{\"a\":1, }", 8, "key"),
+        ] {
+            let err = canonicalize(input).unwrap_err();
+            assert_eq!(ErrorKind::Syntax, err.kind(), "{}", input);
+            assert_eq!((offset, Some(expected)), (err.offset(), err.expected()), "{}", input);
+        }
+        assert_eq!("[1,2]", format("[1 2,]", FormatOptions::new().minify(true)).unwrap());
+
+        // вход по I-JSON: без одиночных суррогатов и повторов ключей
+        for (input, offset, expected) in [
+            (r#"["a\ud800b"]"#, 3, "surrogate pair"),
+            (r#"["\ud800\u0041"]"#, 2, "surrogate pair"),
+            (r#"{"\udc00": 1}"#, 2, "surrogate pair"),
+            (r#"{"a": 1, "b": {"a": 2}, "\u0061": 3}"#, 24, r#"key other than `"\u0061"` or `}`"#),
+        ] {
+            let err = canonicalize(input).unwrap_err();
+            assert_eq!(ErrorKind::Syntax, err.kind(), "{}", input);
+            assert_eq!((offset, Some(expected)), (err.offset(), err.expected()), "{}", input);
+        }
+        assert_eq!(r#"[{"a":1},{"a":2}]"#, canonicalize(r#"[{"a": 1}, {"a": 2}]"#).unwrap());
+        assert_eq!(r#"["😀"]"#, canonicalize(r#"["\ud83d\ude00"]"#).unwrap());
+    }
+
+    #[test]
+    fn sorted_keys() {
+        let input = "{\"b10\": 1, \"b2\": {\"z\": 1, \"a\": [3, 1]}, \"a\": 2, \"b2\": 0}";
+        let sorted = |sort_keys| {
+            let json = JsonOptions {
+                sort_keys,
+                ..JsonOptions::default()
+            };
+            format(input, FormatOptions::new().indent(2).json(json)).unwrap()
+        };
+
+        let expected = "{\n  \"a\": 2,\n  \"b10\": 1,\n  \"b2\": {\n    \"a\": [\n      3,\n      1\n    ],\n    \"z\": 1\n  },\n  \"b2\": 0\n}";
+        assert_eq!(expected, sorted(KeyOrder::Bytewise));
+
+        let expected = "{\n  \"a\": 2,\n  \"b2\": {\n    \"a\": [\n      3,\n      1\n    ],\n    \"z\": 1\n  },\n  \"b2\": 0,\n  \"b10\": 1\n}";
+        assert_eq!(expected, sorted(KeyOrder::Natural));
+        assert_eq!(sorted(KeyOrder::Natural), format(&sorted(KeyOrder::Natural), 2).unwrap());
     }
-}
\ No newline at end of file
+}
@@ -1,4 +1,6 @@
+use std::borrow::Cow;
 use std::fmt::Display;
+use std::io::{Read, Write};
 use std::string::FromUtf8Error;
 
 pub fn add(left: u64, right: u64) -> u64 {
@@ -7,110 +9,891 @@ pub fn add(left: u64, right: u64) -> u64 {
 
 pub mod xml;
 pub mod json;
+pub mod encoding;
+mod stream;
 
 
-pub fn format(body: &str, ident: usize) -> Result<String, String> {
-    if body.contains("</") {
-        xml::format(body, ident)
+// формат документа, определённый по его началу или по content-type
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Json,
+    Xml,
+    Unknown,
+}
+
+impl Format {
+    // формат по значению заголовка content-type, например "application/soap+xml; charset=utf-8"
+    pub fn from_content_type(content_type: &str) -> Option<Format> {
+        let mime = content_type.split(';').next().unwrap_or("").trim();
+        let mime = mime.to_ascii_lowercase();
+        let subtype = mime.rsplit(['/', '+']).next().unwrap_or("");
+
+        match subtype {
+            "json" => Some(Format::Json),
+            "xml" => Some(Format::Xml),
+            _ => None,
+        }
+    }
+}
+
+const BOM: char = '\u{feff}';
+
+// глубина вложенности массивов, объектов и элементов по умолчанию. Разбор и
+// вывод рекурсивны, поэтому более глубокий документ - ошибка, а не
+// переполнение стека
+pub const DEFAULT_MAX_DEPTH: usize = 128;
+
+// наибольшая допустимая глубина вложенности: столько рекурсивный разбор и
+// вывод проходят и в отладочной сборке на стеке потока в 2 МБ
+pub const MAX_DEPTH_LIMIT: usize = 256;
+
+// перевод строки на выходе
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum LineEnding {
+    #[default]
+    Lf,
+    CrLf,
+    // преобладающий во входе, при равенстве или без переводов строк - \n
+    Preserve,
+}
+
+impl LineEnding {
+    // преобладающий перевод строки во входе
+    pub fn dominant(body: &str) -> LineEnding {
+        let total = body.matches('\n').count();
+        let crlf = body.matches("\r\n").count();
+        if crlf > total - crlf {
+            LineEnding::CrLf
+        } else {
+            LineEnding::Lf
+        }
+    }
+
+    fn as_str(&self) -> &'static str {
+        match self {
+            LineEnding::CrLf => "\r\n",
+            // Preserve заменяется на конкретное значение до форматирования
+            LineEnding::Lf | LineEnding::Preserve => "\n",
+        }
+    }
+}
+
+// настройки вывода, общие для всех форматов, и настройки отдельных форматов
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FormatOptions {
+    // пробелов на уровень вложенности, при отступе табуляцией - ширина табуляции
+    indent: usize,
+    tabs: bool,
+    line_ending: LineEnding,
+    // None - как получилось у форматтера: у xml перевод строки в конце есть, у json нет
+    trailing_newline: Option<bool>,
+    // пока используется только для переноса атрибутов xml
+    max_width: Option<usize>,
+    // допустимая глубина вложенности при разборе
+    max_depth: usize,
+    // всё в одну строку без незначащих пробелов. В xml пробельный текст
+    // отбрасывается только между дочерними элементами без текста, в смешанном
+    // содержимом серии пробелов сжимаются до одного пробела
+    minify: bool,
+    // кодировка входа format_bytes, None - определить по входу
+    encoding: Option<encoding::Encoding>,
+    // вывод format_bytes в кодировке входа, а не в utf-8
+    keep_encoding: bool,
+    json: json::JsonOptions,
+    xml: xml::XmlOptions,
+}
+
+impl Default for FormatOptions {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl FormatOptions {
+    pub fn new() -> Self {
+        Self {
+            indent: 4,
+            tabs: false,
+            line_ending: LineEnding::default(),
+            trailing_newline: None,
+            max_width: None,
+            max_depth: DEFAULT_MAX_DEPTH,
+            minify: false,
+            encoding: None,
+            keep_encoding: false,
+            json: json::JsonOptions::default(),
+            xml: xml::XmlOptions::default(),
+        }
+    }
+
+    pub fn indent(mut self, indent: usize) -> Self {
+        self.indent = indent;
+        self
+    }
+
+    pub fn tabs(mut self, tabs: bool) -> Self {
+        self.tabs = tabs;
+        self
+    }
+
+    pub fn line_ending(mut self, line_ending: LineEnding) -> Self {
+        self.line_ending = line_ending;
+        self
+    }
+
+    pub fn trailing_newline(mut self, trailing_newline: bool) -> Self {
+        self.trailing_newline = Some(trailing_newline);
+        self
+    }
+
+    pub fn max_width(mut self, max_width: usize) -> Self {
+        self.max_width = Some(max_width);
+        self
+    }
+
+    // значения больше MAX_DEPTH_LIMIT заменяются на MAX_DEPTH_LIMIT: глубже
+    // рекурсивный разбор может переполнить стек
+    pub fn max_depth(mut self, max_depth: usize) -> Self {
+        self.max_depth = max_depth.min(MAX_DEPTH_LIMIT);
+        self
+    }
+
+    pub fn minify(mut self, minify: bool) -> Self {
+        self.minify = minify;
+        self
+    }
+
+    pub fn encoding(mut self, encoding: encoding::Encoding) -> Self {
+        self.encoding = Some(encoding);
+        self
+    }
+
+    pub fn keep_encoding(mut self, keep_encoding: bool) -> Self {
+        self.keep_encoding = keep_encoding;
+        self
+    }
+
+    pub fn json(mut self, json: json::JsonOptions) -> Self {
+        self.json = json;
+        self
+    }
+
+    pub fn xml(mut self, xml: xml::XmlOptions) -> Self {
+        self.xml = xml;
+        self
+    }
+
+    // настройки для конкретного входа: Preserve заменяется на его перевод строки
+    fn for_input(mut self, body: &str) -> Self {
+        if self.line_ending == LineEnding::Preserve {
+            self.line_ending = LineEnding::dominant(body);
+        }
+        self
+    }
+
+    // отступ для уровня вложенности
+    fn indentation(&self, level: usize) -> String {
+        if self.tabs {
+            "\t".repeat(level)
+        } else {
+            " ".repeat(self.indent * level)
+        }
+    }
+
+    // ширина отступа в символах, табуляция считается за indent символов
+    fn indentation_width(&self, level: usize) -> usize {
+        self.indent * level
+    }
+
+    // отступ строки на уровне level в конец res, при minify отступов нет
+    fn indent_line(&self, res: &mut String, level: usize) {
+        if self.minify {
+            return;
+        }
+        let (ch, width) = if self.tabs { ('\t', level) } else { (' ', self.indent * level) };
+        res.extend(std::iter::repeat_n(ch, width));
+    }
+
+    // строка результата с отступом и переводом строки в конец res. Вывод
+    // дописывается в один буфер, чтобы вложенные блоки не копировались на
+    // каждом уровне вложенности
+    fn line(&self, res: &mut String, parsed: &str, level: usize) {
+        self.indent_line(res, level);
+        res.push_str(parsed);
+        res.push_str(self.newline());
+    }
+
+    // перевод строки между строками результата, при minify строк нет
+    fn newline(&self) -> &'static str {
+        if self.minify {
+            ""
+        } else {
+            self.line_ending.as_str()
+        }
+    }
+
+    // содержимое узла в конец res, переводы строк в нём (\n, \r\n и одиночный
+    // \r) заменены на line_ending, чтобы в выводе не было разных переводов
+    // строк. При minify тоже: переводы строк внутри узлов значимы
+    fn push_lines(&self, res: &mut String, text: &str) {
+        let mut chars = text.chars().peekable();
+        while let Some(ch) = chars.next() {
+            match ch {
+                '\r' => {
+                    chars.next_if_eq(&'\n');
+                    res.push_str(self.line_ending.as_str());
+                }
+                '\n' => res.push_str(self.line_ending.as_str()),
+                ch => res.push(ch),
+            }
+        }
+    }
+
+    // перевод строки в конце документа согласно trailing_newline
+    fn finish(&self, res: String) -> String {
+        let ending = self.line_ending.as_str();
+        match self.trailing_newline {
+            Some(true) if !res.ends_with(ending) => res + ending,
+            Some(false) => res.trim_end_matches(ending).to_string(),
+            _ => res,
+        }
+    }
+}
+
+// число пробелов на уровень - самый короткий способ задать настройки
+impl From<usize> for FormatOptions {
+    fn from(indent: usize) -> Self {
+        Self::new().indent(indent)
+    }
+}
+
+// определение формата по первым символам документа
+pub fn detect(body: &str) -> Format {
+    let start = body.trim_start_matches(BOM);
+    detect_start(start.trim_start_matches(|ch: char| ch.is_ascii_whitespace()))
+}
+
+// формат по началу документа после метки порядка байтов и пробелов; так же
+// формат определяет format_stream
+fn detect_start(start: &str) -> Format {
+    let Some(first) = start.chars().next() else {
+        return Format::Unknown;
+    };
+
+    match format_of(first) {
+        // литерал json узнаётся только целым словом
+        Format::Json if first.is_alphabetic() => {
+            let word = start.split(|ch: char| !ch.is_alphabetic()).next().unwrap_or("");
+            match word {
+                "true" | "false" | "null" | "TRUE" | "FALSE" | "NULL" => Format::Json,
+                _ => Format::Unknown,
+            }
+        }
+        format => format,
+    }
+}
+
+// формат по первому значащему символу документа
+fn format_of(first: char) -> Format {
+    match first {
+        '<' => Format::Xml,
+        '{' | '[' | '"' | '-' | '0'..='9' | 't' | 'f' | 'n' | 'T' | 'F' | 'N' => Format::Json,
+        _ => Format::Unknown,
+    }
+}
+
+// content-type, если он известен, важнее содержимого
+pub fn detect_with_content_type(body: &str, content_type: &str) -> Format {
+    Format::from_content_type(content_type).unwrap_or_else(|| detect(body))
+}
+
+pub fn format(body: &str, options: impl Into<FormatOptions>) -> Result<String, FormatError> {
+    format_as(body, detect(body), options)
+}
+
+pub fn format_with_content_type(
+    body: &str,
+    content_type: &str,
+    options: impl Into<FormatOptions>,
+) -> Result<String, FormatError> {
+    format_as(body, detect_with_content_type(body, content_type), options)
+}
+
+pub fn format_as(
+    body: &str,
+    format: Format,
+    options: impl Into<FormatOptions>,
+) -> Result<String, FormatError> {
+    let body = body.trim_start_matches(BOM);
+    match format {
+        Format::Json => json::format(body, options),
+        Format::Xml => xml::format(body, options),
+        Format::Unknown => Err(unknown_format(body)),
+    }
+}
+
+// каноническая форма: RFC 8785 для json, Canonical XML 1.0 для xml
+pub fn canonicalize(body: &str) -> Result<String, FormatError> {
+    canonicalize_as(body, detect(body))
+}
+
+pub fn canonicalize_as(body: &str, format: Format) -> Result<String, FormatError> {
+    canonicalize_as_with(body, format, FormatOptions::new())
+}
+
+// то же с ограничением вложенности options.max_depth, остальные настройки
+// каноническую форму не меняют
+pub fn canonicalize_with(
+    body: &str,
+    options: impl Into<FormatOptions>,
+) -> Result<String, FormatError> {
+    canonicalize_as_with(body, detect(body), options)
+}
+
+pub fn canonicalize_as_with(
+    body: &str,
+    format: Format,
+    options: impl Into<FormatOptions>,
+) -> Result<String, FormatError> {
+    let body = body.trim_start_matches(BOM);
+    match format {
+        Format::Json => json::canonicalize_with(body, options),
+        Format::Xml => xml::canonicalize_with(body, false, options),
+        Format::Unknown => Err(unknown_format(body)),
+    }
+}
+
+fn unknown_format(body: &str) -> FormatError {
+    let start = body.trim_start();
+    let offset = body.len() - start.len();
+    let kind = if start.is_empty() {
+        ErrorKind::UnexpectedEnd
     } else {
-        json::format(body, ident)
+        ErrorKind::Syntax
+    };
+    FormatError::at(kind, body, offset, Some("JSON or XML document"))
+}
+
+// при ошибке разбора возвращает вход без изменений
+pub fn format_lenient(body: &str, options: impl Into<FormatOptions>) -> String {
+    format_lenient_as(body, detect(body), options)
+}
+
+pub fn format_lenient_as(body: &str, format: Format, options: impl Into<FormatOptions>) -> String {
+    format_as(body, format, options).unwrap_or_else(|_| body.to_string())
+}
+
+// форматирование байтов: кодировка задаётся в options, иначе берётся по метке
+// порядка байтов, из объявления <?xml encoding=...?> или считается utf-8.
+// Результат в utf-8 с исправленным объявлением, с keep_encoding - в кодировке
+// входа. Смещения ошибок разбора отсчитываются в раскодированном тексте
+pub fn format_bytes(
+    body: &[u8],
+    options: impl Into<FormatOptions>,
+) -> Result<Vec<u8>, FormatError> {
+    let options = options.into();
+    let (input, bom) = encoding::Encoding::detect(body, options.encoding)?;
+    let text = input.decode(&body[bom..])?;
+
+    let output = match options.keep_encoding {
+        true => input,
+        false => encoding::Encoding::Utf8,
+    };
+    let text = encoding::redeclare(&text, output);
+    let res = format(&text, options)?;
+
+    let mut bytes = match options.keep_encoding {
+        true => body[..bom].to_vec(),
+        false => Vec::new(),
+    };
+    bytes.extend(output.encode(&res)?);
+    Ok(bytes)
+}
+
+// форматирование из reader в writer без чтения всего входа в память; формат
+// определяется по началу входа так же, как в format
+pub fn format_stream<R: Read, W: Write>(
+    mut reader: R,
+    writer: W,
+    options: impl Into<FormatOptions>,
+) -> Result<(), FormatError> {
+    let bom = BOM.to_string();
+    let mut chunk = vec![0; stream::CHUNK];
+    // прочитанное, но ещё не просмотренное начало входа
+    let mut head = Vec::new();
+    let mut at_start = true;
+    // пробелы до первого значащего байта не копятся, учитывается только их позиция
+    let mut skipped = stream::Position::start();
+    let start = loop {
+        let len = match reader.read(&mut chunk) {
+            Ok(len) => len,
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+            Err(e) => {
+                let mut res = FormatError::at(ErrorKind::Io, "", 0, None);
+                res.message = e.to_string();
+                return Err(skipped.shift(res));
+            }
+        };
+        head.extend_from_slice(&chunk[..len]);
+
+        let mut data = head.as_slice();
+        if at_start {
+            // метка порядка байтов может прийти по частям
+            if len > 0 && data.len() < bom.len() && bom.as_bytes().starts_with(data) {
+                continue;
+            }
+            data = data.strip_prefix(bom.as_bytes()).unwrap_or(data);
+            at_start = false;
+        }
+        let spaces = data.iter().take_while(|b| b.is_ascii_whitespace()).count();
+        skipped.advance(std::str::from_utf8(&data[..spaces]).unwrap_or_default());
+        let significant = data[spaces..].to_vec();
+        // литерал json detect_start узнаёт только целым словом, поэтому
+        // слово в начале входа дочитывается до конца
+        let word = significant.iter().take_while(|&&b| b.is_ascii_alphabetic() || b >= 0x80);
+        let word = word.count();
+        let whole = word < significant.len() || word >= stream::LOOKAHEAD;
+        if (!significant.is_empty() && whole) || len == 0 {
+            break significant;
+        }
+        head = significant;
+    };
+
+    let options = options.into();
+    let format = detect_start(&String::from_utf8_lossy(&start));
+    let reader = std::io::Cursor::new(start).chain(reader);
+    match format {
+        Format::Json => {
+            let stream = stream::Stream::<json::JsonStream>::new(options).after(skipped);
+            stream::copy(stream, reader, writer)
+        }
+        Format::Xml => {
+            let stream = stream::Stream::<xml::XmlStream>::new(options).after(skipped);
+            stream::copy(stream, reader, writer)
+        }
+        Format::Unknown => {
+            let mut body = Vec::new();
+            reader.take(stream::LOOKAHEAD as u64).read_to_end(&mut body).unwrap_or_default();
+            Err(skipped.shift(unknown_format(&String::from_utf8_lossy(&body))))
+        }
     }
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorKind {
+    // вход не соответствует грамматике
+    Syntax,
+    // вход закончился раньше документа
+    UnexpectedEnd,
+    // после документа остались лишние символы
+    TrailingCharacters,
+    // вход не является корректным utf-8
+    Encoding,
+    // вложенность глубже max_depth
+    TooDeep,
+    // ошибка чтения входа или записи результата
+    Io,
+}
+
 #[derive(Debug)]
 pub struct FormatError {
+    kind: ErrorKind,
+    // смещение в байтах от начала входа
+    offset: usize,
+    // строка и колонка (в символах), начиная с 1
+    line: usize,
+    column: usize,
+    expected: Option<String>,
     message: String,
 }
 
+impl FormatError {
+    // ошибка на смещении offset исходной строки source
+    fn at(kind: ErrorKind, source: &str, offset: usize, expected: Option<&str>) -> Self {
+        let (line, column) = line_and_column(&source[..offset]);
+        let message = match (kind, expected) {
+            (ErrorKind::UnexpectedEnd, Some(e)) => format!("unexpected end of input, expected {}", e),
+            (ErrorKind::UnexpectedEnd, None) => "unexpected end of input".to_string(),
+            (ErrorKind::TrailingCharacters, _) => "trailing characters".to_string(),
+            (_, Some(e)) => format!("expected {}", e),
+            (_, None) => "syntax error".to_string(),
+        };
+
+        FormatError {
+            kind,
+            offset,
+            line,
+            column,
+            expected: expected.map(|e| e.to_string()),
+            message,
+        }
+    }
+
+    // вход не соответствует грамматике или закончился раньше времени
+    fn syntax(source: &str, error: &ParseError) -> Self {
+        let offset = error.offset(source);
+        if let Some(max_depth) = error.too_deep {
+            let mut res = FormatError::at(ErrorKind::TooDeep, source, offset, None);
+            res.message = format!("nesting deeper than {} levels", max_depth);
+            return res;
+        }
+
+        let kind = if error.rest.is_empty() {
+            ErrorKind::UnexpectedEnd
+        } else {
+            ErrorKind::Syntax
+        };
+        FormatError::at(kind, source, offset, error.describe().as_deref())
+    }
+
+    fn encoding(source: &str, offset: usize, message: String) -> Self {
+        let mut res = FormatError::at(ErrorKind::Encoding, source, offset, None);
+        res.message = message;
+        res
+    }
+
+    // документ разобран, но после него в rest остались не пробельные символы
+    fn trailing(source: &str, rest: &str) -> Self {
+        let offset = source.len() - rest.len();
+        FormatError::at(ErrorKind::TrailingCharacters, source, offset, None)
+    }
+
+    pub fn kind(&self) -> ErrorKind {
+        self.kind
+    }
+
+    pub fn offset(&self) -> usize {
+        self.offset
+    }
+
+    pub fn line(&self) -> usize {
+        self.line
+    }
+
+    pub fn column(&self) -> usize {
+        self.column
+    }
+
+    pub fn expected(&self) -> Option<&str> {
+        self.expected.as_deref()
+    }
+
+    // описание ошибки без позиции
+    pub fn message(&self) -> &str {
+        &self.message
+    }
+}
+
+// разбор всего документа: вход должен быть разобран целиком
+fn parse_document<'a, T>(parser: &impl Parser<'a, T>, body: &'a str) -> Result<T, FormatError> {
+    match parser.parse(body) {
+        ParseResult(Ok(parsed), "", _) => Ok(parsed),
+        // вложенный парсер продвинулся дальше, чем весь документ, - ошибка там
+        ParseResult(Ok(_), rest, Some(e)) if e.rest.len() < rest.len() => {
+            Err(FormatError::syntax(body, &e))
+        }
+        ParseResult(Ok(_), rest, _) => Err(FormatError::trailing(body, rest)),
+        ParseResult(Err(e), _, _) => Err(FormatError::syntax(body, &e)),
+    }
+}
+
+// строка и колонка позиции сразу за концом prefix
+fn line_and_column(prefix: &str) -> (usize, usize) {
+    let line = prefix.matches('\n').count() + 1;
+    let line_start = prefix.rfind('\n').map_or(0, |i| i + 1);
+    let column = prefix[line_start..].chars().count() + 1;
+
+    (line, column)
+}
+
 impl From<FromUtf8Error> for FormatError {
     fn from(err: FromUtf8Error) -> Self {
-        return FormatError {
+        let offset = err.utf8_error().valid_up_to();
+        let valid = std::str::from_utf8(&err.as_bytes()[..offset]).unwrap_or_default();
+        let (line, column) = line_and_column(valid);
+
+        FormatError {
+            kind: ErrorKind::Encoding,
+            offset,
+            line,
+            column,
+            expected: None,
             message: err.to_string(),
-        };
+        }
     }
 }
 
 impl Display for FormatError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{}", self.message.to_string())
+        write!(
+            f,
+            "{} at line {}, column {}",
+            self.message, self.line, self.column
+        )
+    }
+}
+
+impl std::error::Error for FormatError {}
+
+// что ожидалось в месте ошибки. Ошибки создаются на каждой неудачной ветке
+// разбора, поэтому строки для сообщения собираются только в describe
+#[derive(Debug, Clone, PartialEq)]
+enum Expected {
+    // лексема, в сообщении выводится в обратных кавычках
+    Token(&'static str),
+    // описание вроде "value" или "digit"
+    Description(Cow<'static, str>),
+}
+
+impl Display for Expected {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Expected::Token(token) => write!(f, "`{}`", token),
+            Expected::Description(description) => write!(f, "{}", description),
+        }
+    }
+}
+
+// ошибка разбора
+#[derive(Debug, Clone, PartialEq)]
+struct ParseError<'a> {
+    // остаток исходной строки в месте ошибки, по его длине сравниваются позиции
+    rest: &'a str,
+    // что ожидалось в этом месте
+    expected: Vec<Expected>,
+    // превышена глубина вложенности max_depth: разбор прерван, других ошибок нет
+    too_deep: Option<usize>,
+}
+
+impl<'a> ParseError<'a> {
+    fn new(rest: &'a str, expected: impl Into<Cow<'static, str>>) -> Self {
+        Self {
+            rest,
+            expected: vec![Expected::Description(expected.into())],
+            too_deep: None,
+        }
+    }
+
+    // ожидалась лексема token
+    fn token(rest: &'a str, token: &'static str) -> Self {
+        Self {
+            rest,
+            expected: vec![Expected::Token(token)],
+            too_deep: None,
+        }
+    }
+
+    // ошибка без описания ожидаемого, не попадает в итоговое сообщение
+    fn silent(rest: &'a str) -> Self {
+        Self {
+            rest,
+            expected: Vec::new(),
+            too_deep: None,
+        }
+    }
+
+    // вложенность глубже max_depth в месте rest
+    fn too_deep(rest: &'a str, max_depth: usize) -> Self {
+        Self {
+            rest,
+            expected: Vec::new(),
+            too_deep: Some(max_depth),
+        }
+    }
+
+    // смещение ошибки в байтах от начала source
+    fn offset(&self, source: &str) -> usize {
+        source.len() - self.rest.len()
+    }
+
+    // из ошибки и более ранней ошибки earlier остаётся та, что продвинулась дальше
+    // по входу, ожидания ошибок в одной позиции объединяются
+    fn furthest(self, earlier: Option<Self>) -> Self {
+        match earlier {
+            // превышение глубины не уточняется другими вариантами разбора
+            _ if self.too_deep.is_some() => self,
+            Some(earlier) if earlier.too_deep.is_some() => earlier,
+            Some(earlier) if earlier.rest.len() < self.rest.len() => earlier,
+            Some(earlier) if earlier.rest.len() == self.rest.len() => {
+                let mut res = earlier;
+                for e in self.expected {
+                    if !res.expected.contains(&e) {
+                        res.expected.push(e);
+                    }
+                }
+                res
+            }
+            _ => self,
+        }
+    }
+
+    // ожидаемое в виде "`a`, `b` or `c`"
+    fn describe(&self) -> Option<String> {
+        let mut expected: Vec<String> = Vec::new();
+        for e in &self.expected {
+            let e = e.to_string();
+            if !expected.contains(&e) {
+                expected.push(e);
+            }
+        }
+
+        match expected.split_last() {
+            None => None,
+            Some((last, [])) => Some(last.clone()),
+            Some((last, init)) => Some(init.join(", ") + " or " + last),
+        }
+    }
+}
+
+fn furthest_of<'a>(
+    error: Option<ParseError<'a>>,
+    earlier: Option<ParseError<'a>>,
+) -> Option<ParseError<'a>> {
+    match error {
+        Some(e) => Some(e.furthest(earlier)),
+        None => earlier,
+    }
+}
+
+// часть in_string, разобранная до остатка rest
+fn consumed<'a>(in_string: &'a str, rest: &'a str) -> &'a str {
+    &in_string[..in_string.len() - rest.len()]
+}
+
+// смещение подстроки part внутри body
+fn offset_in(body: &str, part: &str) -> usize {
+    part.as_ptr() as usize - body.as_ptr() as usize
+}
+
+// результат разбора: распарсенное значение (срез входа или узел дерева) или ошибка,
+// остаток исходной строки и самая дальняя ошибка, на которой остановились
+// вложенные парсеры при успешном разборе
+struct ParseResult<'a, T = &'a str>(Result<T, ParseError<'a>>, &'a str, Option<ParseError<'a>>);
+
+impl<'a, T> ParseResult<'a, T> {
+    fn ok(parsed: T, rest: &'a str) -> Self {
+        Self(Ok(parsed), rest, None)
+    }
+
+    fn err(error: ParseError<'a>, in_string: &'a str) -> Self {
+        Self(Err(error), in_string, None)
+    }
+
+    // преобразование успешно распарсенного значения
+    fn map<U, F>(self, f: F) -> ParseResult<'a, U>
+    where
+        F: FnOnce(T) -> U,
+    {
+        ParseResult(self.0.map(f), self.1, self.2)
     }
 }
 
-trait Parser {
+// парсеры строятся один раз и разбирают любое количество строк, результат
+// разбора строки со временем жизни 'a может ссылаться на неё без копирования
+trait Parser<'a, T = &'a str> {
     // вход - исходная строка
-    // выход - распарсенная и отформатированная строка, остаток исходной строки
-    fn parse<'a>(&self, in_string: &'a str) -> (Result<String, ()>, &'a str);
+    // выход - распарсенное значение или ошибка, остаток исходной строки
+    fn parse(&self, in_string: &'a str) -> ParseResult<'a, T>;
+}
+
+// тип результата парсера для входа с любым временем жизни: хранимые парсеры
+// не привязаны к конкретному входу, и построенная один раз грамматика разбирает
+// строки с разными временами жизни
+trait Output {
+    type Of<'a>;
+}
+
+// результат - срез входа
+struct Slice;
+
+impl Output for Slice {
+    type Of<'a> = &'a str;
 }
 
-struct BoxedParser<'a> {
-    parser: Box<dyn Parser + 'a>,
+// парсер в куче. Send - чтобы потоковые форматтеры с грамматикой внутри можно
+// было передавать между потоками
+struct BoxedParser<O: Output = Slice> {
+    parser: Box<dyn for<'a> Parser<'a, O::Of<'a>> + Send>,
 }
 
-impl<'a> BoxedParser<'a> {
-    fn new(p: impl Parser + 'a) -> Self {
+impl<O: Output> BoxedParser<O> {
+    fn new(p: impl for<'a> Parser<'a, O::Of<'a>> + Send + 'static) -> Self {
         Self {
             parser: Box::new(p),
         }
     }
 }
 
-impl<'a> Parser for BoxedParser<'a> {
-    fn parse<'b>(&self, in_string: &'b str) -> (Result<String, ()>, &'b str) {
+impl<'a, O: Output> Parser<'a, O::Of<'a>> for BoxedParser<O> {
+    fn parse(&self, in_string: &'a str) -> ParseResult<'a, O::Of<'a>> {
         self.parser.parse(in_string)
     }
 }
 
+// срез внутреннего парсера, преобразованный в значение другого типа
+struct Map<P, F> {
+    p: P,
+    f: F,
+}
+
+impl<P, F> Map<P, F> {
+    fn new(p: P, f: F) -> Self {
+        Self { p, f }
+    }
+}
+
+impl<'a, T, P: Parser<'a>, F: Fn(&'a str) -> T> Parser<'a, T> for Map<P, F> {
+    fn parse(&self, in_string: &'a str) -> ParseResult<'a, T> {
+        self.p.parse(in_string).map(&self.f)
+    }
+}
+
 // Парсинг строки начинающейся с последовательности символов
 struct CharSequence {
-    prefix: String,
+    prefix: &'static str,
 }
 
 impl CharSequence {
-    fn new(pr: String) -> Self {
+    fn new(pr: &'static str) -> Self {
         Self { prefix: pr }
     }
 }
 
-impl Parser for CharSequence {
-    fn parse<'a>(&self, in_string: &'a str) -> (Result<String, ()>, &'a str) {
-        if in_string.is_empty() {
-            return (Err(()), in_string);
-        }
-
-        let expected_len = self.prefix.len();
-
-        return match in_string.get(0..expected_len) {
-            Some(real_prefix) if self.prefix == real_prefix => {
-                (Ok(real_prefix.to_string()), &in_string[expected_len..])
+impl<'a> Parser<'a> for CharSequence {
+    fn parse(&self, in_string: &'a str) -> ParseResult<'a> {
+        match in_string.strip_prefix(self.prefix) {
+            Some(rest) if !in_string.is_empty() => {
+                ParseResult::ok(&in_string[..self.prefix.len()], rest)
             }
-            _ => (Err(()), in_string),
-        };
+            _ => ParseResult::err(ParseError::token(in_string, self.prefix), in_string),
+        }
     }
 }
 
 // Парсинг строки оканчивающейся на символ/символы
 struct AnyExcept {
-    prefix: String,
+    prefix: &'static str,
 }
 
 impl AnyExcept {
-    fn new(prefix: String) -> Self {
+    fn new(prefix: &'static str) -> Self {
         Self { prefix }
     }
 }
 
-impl Parser for AnyExcept {
-    fn parse<'a>(&self, in_string: &'a str) -> (Result<String, ()>, &'a str) {
-        return if let Some(position) = in_string.find(&self.prefix) {
+impl<'a> Parser<'a> for AnyExcept {
+    fn parse(&self, in_string: &'a str) -> ParseResult<'a> {
+        if let Some(position) = in_string.find(self.prefix) {
             if position > 0 {
-                let parsed = &in_string[0..position];
-                (Ok(parsed.to_string()), &in_string[position..])
+                ParseResult::ok(&in_string[0..position], &in_string[position..])
             } else {
-                (Err(()), in_string)
+                ParseResult::err(ParseError::silent(in_string), in_string)
             }
         } else {
-            (Err(()), in_string)
-        };
+            // окончание так и не встретилось - ошибка в конце входа
+            let end = &in_string[in_string.len()..];
+            ParseResult::err(ParseError::token(end, self.prefix), in_string)
+        }
     }
 }
 
@@ -125,186 +908,211 @@ impl<F> AnyChar<F> {
     }
 }
 
-impl<F> Parser for AnyChar<F>
+impl<'a, F> Parser<'a> for AnyChar<F>
 where
     F: Fn(char) -> bool,
 {
-    fn parse<'a>(&self, in_string: &'a str) -> (Result<String, ()>, &'a str) {
-        let mut res = String::new();
-        let mut chars = in_string.chars();
-        while let Some(ch) = chars.next() {
-            if (self.check)(ch) {
-                res.push(ch);
-            } else {
-                break;
-            }
-        }
+    fn parse(&self, in_string: &'a str) -> ParseResult<'a> {
+        let len = in_string
+            .find(|ch: char| !(self.check)(ch))
+            .unwrap_or(in_string.len());
 
-        let len = res.len();
-        return if len == 0 {
-            (Err(()), in_string)
+        if len == 0 {
+            ParseResult::err(ParseError::silent(in_string), in_string)
         } else {
-            (Ok(res), &in_string[len..])
-        };
+            ParseResult::ok(&in_string[..len], &in_string[len..])
+        }
     }
 }
 
-// один или больше раз встречается внутренний парсер
-struct OneOrMore<P> {
-    p: NTimesOrMore<P>,
+// подменяет ожидаемое значение, если внутренний парсер не смог начать разбор,
+// без имени такая ошибка не попадает в итоговое сообщение
+struct Label<P> {
+    p: P,
+    name: Option<&'static str>,
 }
 
-impl<P: Parser> OneOrMore<P> {
-    fn new(p: P) -> Self {
+impl<P> Label<P> {
+    fn new(p: P, name: &'static str) -> Self {
         Self {
-            p: NTimesOrMore::new(p, 1),
+            p,
+            name: Some(name),
         }
     }
+
+    fn hidden(p: P) -> Self {
+        Self { p, name: None }
+    }
 }
 
-#[warn(dead_code)]
-impl<P: Parser> Parser for OneOrMore<P> {
-    fn parse<'a>(&self, in_string: &'a str) -> (Result<String, ()>, &'a str) {
-        self.p.parse(in_string)
+impl<'a, T, P: Parser<'a, T>> Parser<'a, T> for Label<P> {
+    fn parse(&self, in_string: &'a str) -> ParseResult<'a, T> {
+        match self.p.parse(in_string) {
+            ParseResult(Err(e), rest, _) if e.rest.len() == in_string.len() => {
+                let error = match self.name {
+                    Some(name) => ParseError::new(e.rest, name),
+                    None => ParseError::silent(e.rest),
+                };
+                ParseResult::err(error, rest)
+            }
+            res => res,
+        }
     }
 }
 
-struct ZeroOrMore<P> {
+// один или больше раз встречается внутренний парсер. Грамматики json и xml
+// повторы разбирают сами, эти комбинаторы остались только для тестов
+#[cfg(test)]
+struct OneOrMore<P> {
     p: NTimesOrMore<P>,
 }
 
-impl<P> ZeroOrMore<P> {
+#[cfg(test)]
+impl<P> OneOrMore<P> {
     fn new(p: P) -> Self {
         Self {
-            p: NTimesOrMore::new(p, 0),
+            p: NTimesOrMore::new(p, 1),
         }
     }
 }
 
-impl<P: Parser> Parser for ZeroOrMore<P> {
-    fn parse<'a>(&self, in_string: &'a str) -> (Result<String, ()>, &'a str) {
+#[cfg(test)]
+impl<'a, P: Parser<'a>> Parser<'a> for OneOrMore<P> {
+    fn parse(&self, in_string: &'a str) -> ParseResult<'a> {
         self.p.parse(in_string)
     }
 }
 
-// игнорировать всё, что распарсится
-struct Ignore<'a> {
-    parser: BoxedParser<'a>,
+#[cfg(test)]
+struct ZeroOrMore<P> {
+    p: NTimesOrMore<P>,
 }
 
-impl<'a> Ignore<'a> {
-    fn new(p: impl Parser + 'a) -> Self {
+#[cfg(test)]
+impl<P> ZeroOrMore<P> {
+    fn new(p: P) -> Self {
         Self {
-            parser: BoxedParser::new(p),
+            p: NTimesOrMore::new(p, 0),
         }
     }
 }
 
-impl<'a> Parser for Ignore<'a> {
-    fn parse<'b>(&self, in_string: &'b str) -> (Result<String, ()>, &'b str) {
-        let res = self.parser.parse(in_string);
-        return if let Ok(_) = res.0 {
-            (Ok("".to_string()), res.1)
-        } else {
-            (Err(()), in_string)
-        };
+#[cfg(test)]
+impl<'a, P: Parser<'a>> Parser<'a> for ZeroOrMore<P> {
+    fn parse(&self, in_string: &'a str) -> ParseResult<'a> {
+        self.p.parse(in_string)
     }
 }
 
-// последовательные парсеры
-struct And<'a> {
-    list: Vec<BoxedParser<'a>>,
+// последовательные парсеры, результат - срез входа от начала первого
+// до конца последнего
+struct And {
+    list: Vec<BoxedParser>,
 }
 
-impl<'a> And<'a> {
+impl And {
     fn new() -> Self {
         Self { list: Vec::new() }
     }
 
-    fn add_parser(&mut self, p: impl Parser + 'a) {
-        self.list.push(BoxedParser::new(p));
+    fn add_parser(&mut self, p: impl for<'a> Parser<'a> + Send + 'static) {
+        self.list.push(BoxedParser::<Slice>::new(p));
     }
 }
 
-impl<'a> Parser for And<'a> {
-    fn parse<'b>(&self, in_string: &'b str) -> (Result<String, ()>, &'b str) {
-        let mut res = String::new();
+impl<'a> Parser<'a> for And {
+    fn parse(&self, in_string: &'a str) -> ParseResult<'a> {
         let mut rest = in_string;
+        let mut furthest = None;
 
         for p in &self.list {
             match p.parse(rest) {
-                (Ok(parsed), r) => {
-                    res += parsed.as_str();
+                ParseResult(Ok(_), r, f) => {
                     rest = r;
+                    furthest = furthest_of(f, furthest);
+                }
+                // ошибка элемента последовательности, если только кто-то из
+                // предыдущих не продвинулся дальше
+                ParseResult(Err(e), _, _) => {
+                    return ParseResult::err(e.furthest(furthest), in_string);
                 }
-                _ => return (Err(()), in_string),
             }
         }
 
-        return (Ok(res), rest);
+        ParseResult(Ok(consumed(in_string, rest)), rest, furthest)
     }
 }
 
-struct Or<'a> {
-    list: Vec<BoxedParser<'a>>,
+// первый успешно разобравший вход из альтернатив, O - тип их результата
+struct Or<O: Output = Slice> {
+    list: Vec<BoxedParser<O>>,
 }
 
-impl<'a> Or<'a> {
+impl<O: Output> Or<O> {
     fn new() -> Self {
         Self { list: Vec::new() }
     }
 
-    fn add_parser(&mut self, p: impl Parser + 'a) {
+    fn add_parser(&mut self, p: impl for<'a> Parser<'a, O::Of<'a>> + Send + 'static) {
         self.list.push(BoxedParser::new(p));
     }
 }
 
-impl<'a> Parser for Or<'a> {
-    fn parse<'b>(&self, in_string: &'b str) -> (Result<String, ()>, &'b str) {
+impl<'a, O: Output> Parser<'a, O::Of<'a>> for Or<O> {
+    fn parse(&self, in_string: &'a str) -> ParseResult<'a, O::Of<'a>> {
+        let mut furthest: Option<ParseError> = None;
         for p in &self.list {
-            let (ok, rest) = p.parse(in_string);
-            if ok.is_ok() {
-                return (ok, rest);
+            match p.parse(in_string) {
+                ParseResult(Ok(parsed), rest, f) => {
+                    return ParseResult(Ok(parsed), rest, furthest_of(f, furthest));
+                }
+                ParseResult(Err(e), _, _) => furthest = Some(e.furthest(furthest)),
             }
         }
 
-        return (Err(()), in_string);
+        match furthest {
+            Some(e) => ParseResult::err(e, in_string),
+            None => ParseResult::err(ParseError::silent(in_string), in_string),
+        }
     }
 }
 
+#[cfg(test)]
 struct NTimesOrMore<P> {
     p: P,
     n: usize,
 }
 
+#[cfg(test)]
 impl<P> NTimesOrMore<P> {
     fn new(p: P, n: usize) -> Self {
         Self { p, n }
     }
 }
 
-impl<P: Parser> Parser for NTimesOrMore<P> {
-    fn parse<'a>(&self, in_string: &'a str) -> (Result<String, ()>, &'a str) {
+#[cfg(test)]
+impl<'a, P: Parser<'a>> Parser<'a> for NTimesOrMore<P> {
+    fn parse(&self, in_string: &'a str) -> ParseResult<'a> {
         let mut rest = in_string;
-        let mut res = String::new();
+        let mut furthest = None;
 
         let mut amount = 0;
-        loop {
-            let tmp_res = self.p.parse(rest);
-            if tmp_res.0.is_err() {
-                break;
+        let stop = loop {
+            match self.p.parse(rest) {
+                ParseResult(Ok(_), r, f) => {
+                    rest = r;
+                    furthest = furthest_of(f, furthest);
+                    amount += 1;
+                }
+                ParseResult(Err(e), _, _) => break e.furthest(furthest),
             }
+        };
 
-            res += tmp_res.0.unwrap().as_str();
-            rest = tmp_res.1;
-            amount += 1;
-        }
-
-        return if self.n <= amount {
-            (Ok(res), rest)
+        if self.n <= amount {
+            ParseResult(Ok(consumed(in_string, rest)), rest, Some(stop))
         } else {
-            (Err(()), in_string)
-        };
+            ParseResult::err(stop, in_string)
+        }
     }
 }
 
@@ -318,114 +1126,226 @@ impl<P> ZeroOrOne<P> {
     }
 }
 
-impl<P: Parser> Parser for ZeroOrOne<P> {
-    fn parse<'a>(&self, in_string: &'a str) -> (Result<String, ()>, &'a str) {
-        let mut rest = in_string;
-        let mut res = String::new();
-
-        let mut amount = 0;
-        loop {
-            let tmp_res = self.p.parse(rest);
-            if tmp_res.0.is_err() {
-                break;
-            }
+impl<'a, P: Parser<'a>> Parser<'a> for ZeroOrOne<P> {
+    fn parse(&self, in_string: &'a str) -> ParseResult<'a> {
+        let (res, rest, furthest) = match self.p.parse(in_string) {
+            ParseResult(Ok(parsed), r, f) => (parsed, r, f),
+            ParseResult(Err(e), _, _) => return ParseResult(Ok(""), in_string, Some(e)),
+        };
 
-            res += tmp_res.0.unwrap().as_str();
-            rest = tmp_res.1;
-            amount += 1;
+        match self.p.parse(rest) {
+            // повторное вхождение
+            ParseResult(Ok(_), _, _) => ParseResult::err(ParseError::silent(rest), in_string),
+            ParseResult(Err(e), _, _) => ParseResult(Ok(res), rest, Some(e.furthest(furthest))),
         }
-
-        return if amount <= 1 {
-            (Ok(res), rest)
-        } else {
-            (Err(()), in_string)
-        };
     }
 }
 
+// что делать с \uD800-\uDFFF без пары
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SurrogatePolicy {
+    // заменить на U+FFFD
+    Replace,
+    // оставить экранированным как есть
+    #[default]
+    Keep,
+    // вернуть ошибку
+    Error,
+}
+
+// раскодирование \uXXXX в utf-8, одиночные суррогаты остаются экранированными
 pub fn unescape_utf8(in_string: &str) -> String {
-    let in_len = in_string.len();
-    if in_len < 6 {
-        return String::from(in_string);
+    unescape(in_string, SurrogatePolicy::Keep).unwrap_or_else(|_| in_string.to_string())
+}
+
+pub fn unescape_utf8_with(
+    in_string: &str,
+    policy: SurrogatePolicy,
+) -> Result<String, FormatError> {
+    unescape(in_string, policy).map_err(|offset| {
+        let message = format!("lone surrogate `{}`", &in_string[offset..offset + 6]);
+        FormatError::encoding(in_string, offset, message)
+    })
+}
+
+// код из четырёх шестнадцатеричных цифр в начале строки
+fn hex_code(s: &str) -> Option<u32> {
+    let digits = s.get(0..4)?;
+    if digits.bytes().all(|b| b.is_ascii_hexdigit()) {
+        u32::from_str_radix(digits, 16).ok()
+    } else {
+        None
     }
+}
 
-    let max_idx = in_len - 6;
-    let mut res: Vec<u8> = Vec::with_capacity(in_len);
-    let in_bytes = in_string.as_bytes();
+// ошибка - смещение одиночного суррогата
+fn unescape(in_string: &str, policy: SurrogatePolicy) -> Result<String, usize> {
+    let mut res = String::with_capacity(in_string.len());
+    // начало ещё не скопированной в res части
+    let mut copied = 0;
     let mut i = 0;
-    while i <= max_idx {
-        if in_bytes[i] == '\\' as u8 && in_bytes[i + 1] == 'u' as u8 {
-            let str_num = unsafe { std::str::from_utf8_unchecked(&in_bytes[i + 2..i + 6]) };
-            if let Ok(n) = u32::from_str_radix(str_num, 16) {
-                res.extend_from_slice(&unicode_to_utf8(n));
-                // if n < 0x255 {
-                //     res.push(n.to_be_bytes()[1]);
-                // } else {
-                //     res.extend_from_slice(&n.to_le_bytes());
-                // };
-
-                i += 6;
+    while let Some(pos) = in_string[i..].find('\\').map(|p| p + i) {
+        let rest = &in_string[pos + 1..];
+        // прочие экранированные символы, в том числе \\, пропускаются целиком
+        let code = match rest.strip_prefix('u').and_then(hex_code) {
+            Some(code) => code,
+            None => {
+                i = pos + 1 + rest.chars().next().map_or(0, char::len_utf8);
                 continue;
             }
-        }
+        };
 
-        res.push(in_bytes[i]);
-        i += 1
-    }
-    res.extend_from_slice(&in_bytes[i..]);
+        let (decoded, len) = match code {
+            0xd800..=0xdbff => {
+                let low = rest[5..].strip_prefix("\\u").and_then(hex_code);
+                match low {
+                    Some(low @ 0xdc00..=0xdfff) => {
+                        let code = 0x10000 + ((code - 0xd800) << 10) + (low - 0xdc00);
+                        (char::from_u32(code), 12)
+                    }
+                    _ => (None, 6),
+                }
+            }
+            0xdc00..=0xdfff => (None, 6),
+            // кавычка, слеш и управляющие символы не могут быть в строке json как есть
+            0x00..=0x1f | 0x22 | 0x5c => {
+                i = pos + 6;
+                continue;
+            }
+            _ => (char::from_u32(code), 6),
+        };
 
-    String::from_utf8(res).unwrap()
-}
+        let replacement = match (decoded, policy) {
+            (Some(ch), _) => ch,
+            (None, SurrogatePolicy::Replace) => char::REPLACEMENT_CHARACTER,
+            (None, SurrogatePolicy::Keep) => {
+                i = pos + 6;
+                continue;
+            }
+            (None, SurrogatePolicy::Error) => return Err(pos),
+        };
 
-pub fn unicode_to_utf8(mut char: u32) -> Vec<u8> {
-    let mut res = Vec::with_capacity(4);
+        res.push_str(&in_string[copied..pos]);
+        res.push(replacement);
+        i = pos + len;
+        copied = i;
+    }
+    res.push_str(&in_string[copied..]);
 
-    // 10000 001101 001000
-    let mut first_byte_mask = 0b01111111;
-    let mut first_byte_prefix = 0b00000000;
-    loop {
-        let cur_octet = (char & 0b111111) as u8;
-        char >>= 6;
+    Ok(res)
+}
 
-        if first_byte_mask == 0b01111111 && (char == 0 || char == 1) {
-            char <<= 6;
-            res.push(char as u8 | cur_octet);
-        } else {
-            res.push(cur_octet | 0b10000000);
+// раскодирование всех экранированных последовательностей строки json,
+// одиночные суррогаты заменяются на U+FFFD
+fn unescape_all(in_string: &str) -> String {
+    let decoded = unescape(in_string, SurrogatePolicy::Replace).unwrap_or_default();
+    let mut res = String::with_capacity(decoded.len());
+    let mut chars = decoded.chars();
+    while let Some(ch) = chars.next() {
+        if ch != '\\' {
+            res.push(ch);
+            continue;
         }
 
-        if char == 0 {
-            break;
+        match chars.next() {
+            Some('b') => res.push('\u{8}'),
+            Some('f') => res.push('\u{c}'),
+            Some('n') => res.push('\n'),
+            Some('r') => res.push('\r'),
+            Some('t') => res.push('\t'),
+            // оставшиеся после unescape \u - кавычка, слеш и управляющие символы
+            Some('u') => {
+                let code = chars.as_str().get(0..4).and_then(hex_code);
+                match code.and_then(char::from_u32) {
+                    Some(decoded) => {
+                        res.push(decoded);
+                        chars.nth(3);
+                    }
+                    None => res.push_str("\\u"),
+                }
+            }
+            Some(other) => res.push(other),
+            None => res.push('\\'),
         }
+    }
 
-        if first_byte_mask == 0b01111111 {
-            first_byte_mask >>= 2;
-            first_byte_prefix = first_byte_prefix >> 2 | 0b11000000;
-        } else {
-            first_byte_mask >>= 1;
-            first_byte_prefix = first_byte_prefix >> 1 | 0b10000000;
+    res
+}
+
+// строка json в кавычках в записи RFC 8785: всё раскодировано, кроме кавычки,
+// обратного слеша и управляющих символов. Строки с одиночными суррогатами
+// сюда не попадают: при JsonOptions::normalize они ошибка разбора
+fn escape_canonical(in_string: &str) -> String {
+    let inner = in_string
+        .strip_prefix('"')
+        .and_then(|s| s.strip_suffix('"'))
+        .unwrap_or(in_string);
+
+    let mut res = String::with_capacity(in_string.len());
+    res.push('"');
+    for ch in unescape_all(inner).chars() {
+        match ch {
+            '"' => res += "\\\"",
+            '\\' => res += "\\\\",
+            '\u{8}' => res += "\\b",
+            '\u{c}' => res += "\\f",
+            '\n' => res += "\\n",
+            '\r' => res += "\\r",
+            '\t' => res += "\\t",
+            ch if (ch as u32) < 0x20 => res += &format!("\\u{:04x}", ch as u32),
+            ch => res.push(ch),
         }
+    }
+    res.push('"');
 
-        if char & first_byte_mask == char {
-            res.push(char as u8 | first_byte_prefix);
-            break;
+    res
+}
+
+// экранирование всех символов вне ascii, за пределами BMP - суррогатными парами
+fn escape_non_ascii(in_string: &str) -> String {
+    let mut res = String::with_capacity(in_string.len());
+    for ch in in_string.chars() {
+        if ch.is_ascii() {
+            res.push(ch);
+        } else {
+            for unit in ch.encode_utf16(&mut [0; 2]) {
+                res += &format!("\\u{:04x}", unit);
+            }
         }
     }
-    res.reverse();
 
     res
 }
 
+// кодирование символа в utf-8, code не больше 0x10ffff
+pub fn unicode_to_utf8(code: u32) -> Vec<u8> {
+    let tail = |shift: u32| 0b10000000 | ((code >> shift) & 0b111111) as u8;
+
+    match code {
+        0..=0x7f => vec![code as u8],
+        0x80..=0x7ff => vec![0b11000000 | (code >> 6) as u8, tail(0)],
+        0x800..=0xffff => vec![0b11100000 | (code >> 12) as u8, tail(6), tail(0)],
+        _ => vec![
+            0b11110000 | ((code >> 18) & 0b111) as u8,
+            tail(12),
+            tail(6),
+            tail(0),
+        ],
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::{
-        unescape_utf8, unicode_to_utf8, And, AnyChar, CharSequence, OneOrMore, Or, Parser,
-        ZeroOrOne,
+        detect, detect_with_content_type, format, format_stream, format_with_content_type,
+        unescape_utf8, unescape_utf8_with, unicode_to_utf8, And, AnyChar, CharSequence, ErrorKind,
+        Format, FormatError, FormatOptions, LineEnding, OneOrMore, Or, Parser, SurrogatePolicy,
+        ZeroOrMore, ZeroOrOne, MAX_DEPTH_LIMIT,
     };
 
     #[test]
     fn one_or_more() {
-        let parser = OneOrMore::new(CharSequence::new("ha".to_string()));
+        let parser = OneOrMore::new(CharSequence::new("ha"));
 
         let res = parser.parse("ha12345");
         assert_eq!("ha", res.0.unwrap());
@@ -439,7 +1359,7 @@ mod tests {
     #[test]
     fn any_char() {
         let parser = AnyChar::new(|ch: char| {
-            return ch == 'a';
+            ch == 'a'
         });
 
         let res = parser.parse("aaab");
@@ -450,24 +1370,26 @@ mod tests {
     #[test]
     fn parse_sequence() {
         let mut parser = And::new();
-        parser.add_parser(CharSequence::new("he".to_string()));
-        parser.add_parser(CharSequence::new("ll".to_string()));
-        parser.add_parser(CharSequence::new("o".to_string()));
+        parser.add_parser(CharSequence::new("he"));
+        parser.add_parser(CharSequence::new("ll"));
+        parser.add_parser(CharSequence::new("o"));
 
         let res = parser.parse("hello");
         assert_eq!("hello", res.0.unwrap());
         assert_eq!("", res.1);
 
         let res = parser.parse("help");
-        assert_eq!(true, res.0.is_err());
         assert_eq!("help", res.1);
+        let err = res.0.unwrap_err();
+        assert_eq!(2, err.offset("help"));
+        assert_eq!(Some("`ll`".to_string()), err.describe());
     }
 
     #[test]
     fn parse_or() {
-        let mut parser = Or::new();
-        parser.add_parser(CharSequence::new("hello".to_string()));
-        parser.add_parser(CharSequence::new("goodbye".to_string()));
+        let mut parser: Or = Or::new();
+        parser.add_parser(CharSequence::new("hello"));
+        parser.add_parser(CharSequence::new("goodbye"));
 
         let res = parser.parse("hello");
         assert_eq!("hello", res.0.unwrap());
@@ -478,23 +1400,205 @@ mod tests {
         assert_eq!("", res.1);
 
         let res = parser.parse("help");
-        assert_eq!(true, res.0.is_err());
+        assert!(res.0.is_err());
         assert_eq!("help", res.1);
     }
 
+    #[test]
+    fn or_furthest_error() {
+        let mut first = And::new();
+        first.add_parser(CharSequence::new("a"));
+        first.add_parser(CharSequence::new("b"));
+        let mut second = And::new();
+        second.add_parser(CharSequence::new("a"));
+        second.add_parser(CharSequence::new("c"));
+        let mut third = And::new();
+        third.add_parser(CharSequence::new("ab"));
+        third.add_parser(CharSequence::new("d"));
+
+        let mut parser: Or = Or::new();
+        parser.add_parser(first);
+        parser.add_parser(second);
+        parser.add_parser(third);
+
+        let err = parser.parse("ax").0.unwrap_err();
+        assert_eq!(1, err.offset("ax"));
+        assert_eq!(Some("`b` or `c`".to_string()), err.describe());
+
+        let res = parser.parse("acd");
+        assert_eq!("ac", res.0.unwrap());
+        assert_eq!("d", res.1);
+    }
+
+    #[test]
+    fn repetition_keeps_furthest_error() {
+        let mut item = And::new();
+        item.add_parser(CharSequence::new("("));
+        item.add_parser(CharSequence::new(")"));
+
+        let mut parser = And::new();
+        parser.add_parser(ZeroOrMore::new(item));
+        parser.add_parser(CharSequence::new(";"));
+
+        let err = parser.parse("()()(x").0.unwrap_err();
+        assert_eq!(5, err.offset("()()(x"));
+        assert_eq!(Some("`)`".to_string()), err.describe());
+
+        let err = parser.parse("()()x").0.unwrap_err();
+        assert_eq!(Some("`(` or `;`".to_string()), err.describe());
+    }
+
     #[test]
     fn zero_or_one() {
-        let parser = ZeroOrOne::new(CharSequence::new("ha".to_string()));
+        let parser = ZeroOrOne::new(CharSequence::new("ha"));
 
         let res = parser.parse("ha12345");
         assert_eq!("ha", res.0.unwrap());
         assert_eq!("12345", res.1);
 
         let res = parser.parse("hahahaha12345");
-        assert_eq!(true, res.0.is_err());
+        assert!(res.0.is_err());
         assert_eq!("hahahaha12345", res.1);
     }
 
+    #[test]
+    fn detect_format() {
+        assert_eq!(Format::Xml, detect("<a/>"));
+        assert_eq!(Format::Xml, detect("\u{feff}<?xml version=\"1.0\"?><a></a>"));
+        assert_eq!(Format::Xml, detect("  \n<a>text</a>"));
+        assert_eq!(Format::Json, detect("{\"html\": \"<b>bold</b>\"}"));
+        assert_eq!(Format::Json, detect("\u{feff} [1, 2]"));
+        assert_eq!(Format::Json, detect("-12.5"));
+        assert_eq!(Format::Json, detect("null"));
+        assert_eq!(Format::Unknown, detect("nothing"));
+        assert_eq!(Format::Unknown, detect(""));
+
+        let ct = "application/soap+xml; charset=utf-8";
+        assert_eq!(Some(Format::Xml), Format::from_content_type(ct));
+        assert_eq!(Some(Format::Json), Format::from_content_type("Application/JSON"));
+        assert_eq!(Some(Format::Json), Format::from_content_type("application/problem+json"));
+        assert_eq!(None, Format::from_content_type("text/plain"));
+        assert_eq!(Format::Json, detect_with_content_type("[1]", "text/plain"));
+        assert_eq!(Format::Xml, detect_with_content_type("[1]", "text/xml"));
+    }
+
+    #[test]
+    fn format_detected() {
+        let res = format("{\"html\": \"<b>bold</b>\"}", 4);
+        assert_eq!("{\n    \"html\": \"<b>bold</b>\"\n}", res.unwrap());
+
+        let res = format("\u{feff}<?xml version=\"1.0\"?><a><b/></a>", 2);
+        assert_eq!("<?xml version=\"1.0\"?>\n<a>\n  <b/>\n</a>\n", res.unwrap());
+
+        let err = format("  plain text", 4).unwrap_err();
+        assert_eq!(ErrorKind::Syntax, err.kind());
+        assert_eq!(2, err.offset());
+
+        let err = format_with_content_type("{}", "application/xml", 4).unwrap_err();
+        assert_eq!(ErrorKind::Syntax, err.kind());
+    }
+
+    #[test]
+    fn format_options() {
+        let json = "{\"a\": [1, {\"b\": {}}]}";
+        let xml = "<a><b x=\"1\" y=\"2\"/></a>";
+
+        let options = FormatOptions::new().tabs(true).line_ending(LineEnding::CrLf);
+        let res = format(json, options).unwrap();
+        assert_eq!("{\r\n\t\"a\": [\r\n\t\t1,\r\n\t\t{\r\n\t\t\t\"b\": {}\r\n\t\t}\r\n\t]\r\n}", res);
+
+        let res = format(xml, options.max_width(10)).unwrap();
+        assert_eq!("<a>\r\n\t<b\r\n\t x=\"1\"\r\n\t y=\"2\"/>\r\n</a>\r\n", res);
+
+        let res = format(json, FormatOptions::new().indent(0).trailing_newline(true));
+        assert_eq!("{\n\"a\": [\n1,\n{\n\"b\": {}\n}\n]\n}\n", res.unwrap());
+
+        let res = format(xml, FormatOptions::from(1).trailing_newline(false));
+        assert_eq!("<a>\n <b x=\"1\" y=\"2\"/>\n</a>", res.unwrap());
+    }
+
+    #[test]
+    fn max_depth_limit() {
+        let options = FormatOptions::new().max_depth(100000);
+        let xml = "<a>".repeat(20000) + &"</a>".repeat(20000);
+        let err = format(&xml, options).unwrap_err();
+        assert_eq!(format!("nesting deeper than {} levels", MAX_DEPTH_LIMIT), err.message());
+        assert!(format_stream(xml.as_bytes(), Vec::new(), options).is_err());
+
+        let json = "[".repeat(20000) + &"]".repeat(20000);
+        assert!(format(&json, options).is_err());
+        let json = "[".repeat(MAX_DEPTH_LIMIT) + &"]".repeat(MAX_DEPTH_LIMIT);
+        assert!(format(&json, options).is_ok());
+    }
+
+    #[test]
+    fn minify() {
+        let options = FormatOptions::new().minify(true);
+
+        let json = "{\n    \"a b\": [\n        1,\n        \"x  y\"\n    ],\n    \"c\":   {}\n}";
+        assert_eq!("{\"a b\":[1,\"x  y\"],\"c\":{}}", format(json, options).unwrap());
+
+        let xml = "<?xml version=\"1.0\"?>\n<a>\n    <b  x = \"1\" >  t  </b>\n    <p>Hello <i>big</i> world</p>\n    <c/>\n</a>\n";
+        let expected = "<?xml version=\"1.0\"?><a><b x=\"1\">  t  </b><p>Hello <i>big</i> world</p><c/></a>";
+        assert_eq!(expected, format(xml, options.max_width(10)).unwrap());
+
+        let res = format(xml, options.trailing_newline(true)).unwrap();
+        assert_eq!(format!("{}\n", expected), res);
+
+        // в смешанном содержимом пробелы значимы и сжимаются до одного
+        let res = format("<a>\n  <b>x</b>\n  text\n  <c> <d/> </c>\n</a>", options);
+        assert_eq!("<a> <b>x</b> text <c><d/></c> </a>", res.unwrap());
+
+        let xml = "<p>Hello <i>big</i> <b>x</b></p>";
+        assert_eq!(xml, format(xml, options).unwrap());
+        let res = format("<div>\n  <p>\n    Hello\n    <b>x</b></p>\n</div>", options);
+        assert_eq!("<div><p> Hello <b>x</b></p></div>", res.unwrap());
+    }
+
+    #[test]
+    fn line_endings() {
+        assert_eq!(LineEnding::Lf, LineEnding::dominant("a"));
+        assert_eq!(LineEnding::Lf, LineEnding::dominant("a\r\nb\nc"));
+        assert_eq!(LineEnding::CrLf, LineEnding::dominant("a\r\nb\r\nc\n"));
+
+        let options = FormatOptions::from(2).line_ending(LineEnding::Preserve);
+        let res = format("{\r\n\"a\": [1]\r\n}", options).unwrap();
+        assert_eq!("{\r\n  \"a\": [\r\n    1\r\n  ]\r\n}", res);
+
+        let res = format("<a>\n<b/></a>", options).unwrap();
+        assert_eq!("<a>\n  <b/>\n</a>\n", res);
+
+        let res = format("<a>\r\n<b/></a>", options.trailing_newline(false)).unwrap();
+        assert_eq!("<a>\r\n  <b/>\r\n</a>", res);
+
+        // переводы строк внутри узлов тоже заменяются
+        let options = FormatOptions::from(2).line_ending(LineEnding::CrLf);
+        let xml = "<a><!-- one\ntwo\r --><b>x\ny</b><?p a\nb?><![CDATA[c\r\nd]]></a>";
+        let res = format(xml, options).unwrap();
+        let expect = "<a>\r\n  <!-- one\r\ntwo\r\n -->\r\n  <b>x\r\ny</b>\r\n  <?p a\r\nb?>\r\n  \
+            <![CDATA[c\r\nd]]>\r\n</a>\r\n";
+        assert_eq!(expect, res);
+
+        let res = format("<a>x\r\ny<b/></a>", FormatOptions::new().minify(true)).unwrap();
+        assert_eq!("<a>x y<b/></a>", res);
+        let res = format("<a>\r\n x\ry\n<b/></a>", FormatOptions::from(1)).unwrap();
+        assert_eq!("<a>\n x\ny\n<b/></a>\n", res);
+    }
+
+    #[test]
+    fn error_position() {
+        let err = format("{\n  \"имя\": \"a\"} ]", 4).unwrap_err();
+        assert_eq!(ErrorKind::TrailingCharacters, err.kind());
+        assert_eq!(19, err.offset());
+        assert_eq!(2, err.line());
+        assert_eq!(15, err.column());
+
+        let bytes = vec![b'a', b'\n', b'b', 0xff];
+        let err = FormatError::from(String::from_utf8(bytes).unwrap_err());
+        assert_eq!(ErrorKind::Encoding, err.kind());
+        assert_eq!((3, 2, 2), (err.offset(), err.line(), err.column()));
+    }
+
     #[test]
     fn unescape_unicode_test() {
         let out = unescape_utf8(r"Hello world\");
@@ -506,11 +1610,17 @@ mod tests {
         let out = unescape_utf8(r"Hello \u0l38");
         assert_eq!(r"Hello \u0l38", out);
 
+        let out = unescape_utf8(r"\é \u+041 \u0041");
+        assert_eq!(r"\é \u+041 A", out);
+
         let out = unescape_utf8(r"привет");
         assert_eq!(r"привет", out);
 
         let out = unescape_utf8(r"\u041c\u0430\u0440\u0438\u044f");
         assert_eq!(r"Мария", out);
+
+        let out = unescape_utf8(r"C:\\u0031\\\u0031");
+        assert_eq!(r"C:\\u0031\\1", out);
     }
 
     #[test]
@@ -519,8 +1629,51 @@ mod tests {
         assert_eq!(vec![0xc2, 0xa2], unicode_to_utf8(0xa2));
         assert_eq!(vec![0xc2, 0xa2], unicode_to_utf8(0xa2));
         assert_eq!(vec![0xe2, 0x82, 0xac], unicode_to_utf8(0x20ac));
+        assert_eq!(vec![0x41], unicode_to_utf8(0x41));
+        assert_eq!(vec![0xf0, 0x90, 0x8d, 0x88], unicode_to_utf8(0x10348));
+
+        for ch in (0..=0x10ffff).filter_map(char::from_u32) {
+            let mut buf = [0; 4];
+            let expected = ch.encode_utf8(&mut buf).as_bytes();
+            assert_eq!(expected, unicode_to_utf8(ch as u32).as_slice());
+        }
+    }
+
+    #[test]
+    fn unescape_surrogates() {
+        assert_eq!("😀", unescape_utf8(r"\ud83d\ude00"));
+        assert_eq!("a𐍈b", unescape_utf8(r"a\uD800\uDF48b"));
+
+        let input = r"x\ud83d y \ude00";
+        assert_eq!(input, unescape_utf8(input));
+        let res = unescape_utf8_with(input, SurrogatePolicy::Replace).unwrap();
+        assert_eq!("x\u{fffd} y \u{fffd}", res);
+        let err = unescape_utf8_with(input, SurrogatePolicy::Error).unwrap_err();
+        assert_eq!(ErrorKind::Encoding, err.kind());
+        assert_eq!(1, err.offset());
+        assert_eq!("lone surrogate `\\ud83d`", err.message());
+
+        // старший суррогат, за которым идёт не младший
+        let res = unescape_utf8_with(r"\ud83d\u0031", SurrogatePolicy::Replace).unwrap();
+        assert_eq!("\u{fffd}1", res);
+        assert_eq!(r"\ud83d", unescape_utf8(r"\ud83d"));
+    }
 
-        // это не работает, но для русского языка - сойдёт и так
-        // assert_eq!(vec![0xf0, 0x90, 0x8d, 0x88], unicode_to_utf8(0x10348));
+    #[test]
+    fn unescape_round_trip() {
+        for ch in (0..=0x10ffff).filter_map(char::from_u32) {
+            let escaped = ch
+                .encode_utf16(&mut [0; 2])
+                .iter()
+                .map(|unit| format!("\\u{:04x}", unit))
+                .collect::<String>();
+
+            let res = unescape_utf8_with(&escaped, SurrogatePolicy::Error).unwrap();
+            if ch < ' ' || ch == '"' || ch == '\\' {
+                assert_eq!(escaped, res);
+            } else {
+                assert_eq!(ch.to_string(), res);
+            }
+        }
     }
 }
@@ -0,0 +1,272 @@
+use std::fs;
+use std::io::{self, Read, Write};
+use std::process::ExitCode;
+
+use prettifier::{
+    canonicalize, canonicalize_as, format, format_as, Format, FormatOptions, LineEnding,
+};
+
+const USAGE: &str = "Usage: prettifier [OPTIONS] [FILE]...
+
+Pretty-prints JSON and XML documents. Reads standard input when no FILE
+is given or FILE is `-`.
+
+Options:
+    --indent <N>         spaces per nesting level (default 4)
+    --tabs               indent with tabs
+    --minify             print each document on one line without insignificant
+                         whitespace
+    --canonical          print the canonical form: RFC 8785 for JSON,
+                         Canonical XML 1.0 for XML
+    --line-ending <lf|crlf|preserve>
+                         line ending of the output (default lf), preserve
+                         keeps the dominant ending of each input
+    --format <json|xml>  skip format detection
+    -i, --in-place       rewrite files instead of printing them
+    --check              print nothing, exit with 1 if any input is not formatted
+    -h, --help           print this help";
+
+#[derive(Debug, PartialEq)]
+struct Args {
+    indent: usize,
+    tabs: bool,
+    minify: bool,
+    canonical: bool,
+    line_ending: LineEnding,
+    format: Option<Format>,
+    in_place: bool,
+    check: bool,
+    files: Vec<String>,
+}
+
+impl Args {
+    fn parse(args: impl Iterator<Item = String>) -> Result<Option<Args>, String> {
+        let mut res = Args {
+            indent: 4,
+            tabs: false,
+            minify: false,
+            canonical: false,
+            line_ending: LineEnding::Lf,
+            format: None,
+            in_place: false,
+            check: false,
+            files: Vec::new(),
+        };
+
+        let mut args = args.peekable();
+        while let Some(arg) = args.next() {
+            // значение опции: "--indent 2" или "--indent=2"
+            let (name, inline_value) = match arg.split_once('=') {
+                Some((name, value)) if name.starts_with("--") => (name.to_string(), Some(value)),
+                _ => (arg.clone(), None),
+            };
+            let mut value = |name: &str| match inline_value {
+                Some(v) => Ok(v.to_string()),
+                None => args.next().ok_or(format!("missing value for {}", name)),
+            };
+
+            match name.as_str() {
+                "-h" | "--help" => return Ok(None),
+                "-i" | "--in-place" => res.in_place = true,
+                "--check" => res.check = true,
+                "--indent" => {
+                    let v = value("--indent")?;
+                    res.indent = v.parse().map_err(|_| format!("invalid indent `{}`", v))?;
+                }
+                "--tabs" => res.tabs = true,
+                "--minify" => res.minify = true,
+                "--canonical" => res.canonical = true,
+                "--line-ending" => {
+                    res.line_ending = match value("--line-ending")?.as_str() {
+                        "lf" => LineEnding::Lf,
+                        "crlf" => LineEnding::CrLf,
+                        "preserve" => LineEnding::Preserve,
+                        v => return Err(format!("unknown line ending `{}`", v)),
+                    }
+                }
+                "--format" => {
+                    res.format = match value("--format")?.as_str() {
+                        "json" => Some(Format::Json),
+                        "xml" => Some(Format::Xml),
+                        v => return Err(format!("unknown format `{}`", v)),
+                    }
+                }
+                "-" => res.files.push(arg),
+                _ if arg.starts_with('-') => return Err(format!("unknown option `{}`", arg)),
+                _ => res.files.push(arg),
+            }
+        }
+
+        if res.in_place && (res.files.is_empty() || res.files.iter().any(|f| f == "-")) {
+            return Err("--in-place requires file arguments".to_string());
+        }
+        if res.files.is_empty() {
+            res.files.push("-".to_string());
+        }
+
+        Ok(Some(res))
+    }
+
+    fn format(&self, body: &str) -> Result<String, prettifier::FormatError> {
+        if self.canonical {
+            let res = match self.format {
+                Some(f) => canonicalize_as(body, f),
+                None => canonicalize(body),
+            };
+            return res.map(|res| res + "\n");
+        }
+
+        let options = FormatOptions::new()
+            .indent(self.indent)
+            .tabs(self.tabs)
+            .minify(self.minify)
+            .line_ending(self.line_ending)
+            .trailing_newline(true);
+        match self.format {
+            Some(f) => format_as(body, f, options),
+            None => format(body, options),
+        }
+    }
+}
+
+fn read_input(file: &str) -> io::Result<String> {
+    if file == "-" {
+        let mut body = String::new();
+        io::stdin().read_to_string(&mut body)?;
+        Ok(body)
+    } else {
+        fs::read_to_string(file)
+    }
+}
+
+fn display_name(file: &str) -> &str {
+    if file == "-" {
+        "<stdin>"
+    } else {
+        file
+    }
+}
+
+// обработка одного входа, false - вход с ошибкой или не отформатирован
+fn process(args: &Args, file: &str, out: &mut impl Write) -> bool {
+    let name = display_name(file);
+    let body = match read_input(file) {
+        Ok(body) => body,
+        Err(e) => {
+            eprintln!("{}: {}", name, e);
+            return false;
+        }
+    };
+
+    let formatted = match args.format(&body) {
+        Ok(formatted) => formatted,
+        Err(e) => {
+            eprintln!("{}:{}:{}: {}", name, e.line(), e.column(), e.message());
+            return false;
+        }
+    };
+
+    if args.check {
+        if formatted != body {
+            eprintln!("{}: not formatted", name);
+            return false;
+        }
+        return true;
+    }
+
+    let written = if args.in_place {
+        if formatted == body {
+            return true;
+        }
+        fs::write(file, formatted)
+    } else {
+        out.write_all(formatted.as_bytes())
+    };
+
+    if let Err(e) = written {
+        eprintln!("{}: {}", name, e);
+        return false;
+    }
+
+    true
+}
+
+fn main() -> ExitCode {
+    let args = match Args::parse(std::env::args().skip(1)) {
+        Ok(Some(args)) => args,
+        Ok(None) => {
+            println!("{}", USAGE);
+            return ExitCode::SUCCESS;
+        }
+        Err(e) => {
+            eprintln!("prettifier: {}\n\n{}", e, USAGE);
+            return ExitCode::from(2);
+        }
+    };
+
+    let stdout = io::stdout();
+    let mut out = stdout.lock();
+    let mut success = true;
+    for file in &args.files {
+        success &= process(&args, file, &mut out);
+    }
+
+    if success {
+        ExitCode::SUCCESS
+    } else {
+        ExitCode::FAILURE
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Args, Format};
+
+    fn parse(args: &[&str]) -> Result<Option<Args>, String> {
+        Args::parse(args.iter().map(|a| a.to_string()))
+    }
+
+    #[test]
+    fn parse_args() {
+        let args = parse(&[]).unwrap().unwrap();
+        assert_eq!(4, args.indent);
+        assert_eq!(None, args.format);
+        assert_eq!(vec!["-".to_string()], args.files);
+
+        let args = parse(&["--indent", "2", "--format=xml", "--check", "a.xml", "-"])
+            .unwrap()
+            .unwrap();
+        assert_eq!(2, args.indent);
+        assert_eq!(Some(Format::Xml), args.format);
+        assert!(args.check);
+        assert_eq!(vec!["a.xml".to_string(), "-".to_string()], args.files);
+
+        assert_eq!(None, parse(&["a.json", "--help"]).unwrap());
+        assert!(parse(&["--indent"]).is_err());
+        assert!(parse(&["--indent", "two"]).is_err());
+        assert!(parse(&["--format", "yaml"]).is_err());
+        assert!(parse(&["--line-ending", "cr"]).is_err());
+        assert!(parse(&["--verbose"]).is_err());
+        assert!(parse(&["-i"]).is_err());
+        assert!(parse(&["-i", "-"]).is_err());
+    }
+
+    #[test]
+    fn format_adds_final_newline() {
+        let args = parse(&["--indent", "2"]).unwrap().unwrap();
+        assert_eq!("[\n  1\n]\n", args.format("[1]").unwrap());
+
+        let args = parse(&["--format", "json"]).unwrap().unwrap();
+        assert!(args.format("<a/>").is_err());
+
+        let args = parse(&["--tabs", "--line-ending=preserve"]).unwrap().unwrap();
+        assert_eq!("<a>\r\n\t<b/>\r\n</a>\r\n", args.format("<a>\r\n<b/></a>").unwrap());
+
+        let args = parse(&["--minify"]).unwrap().unwrap();
+        assert_eq!("[1,2]\n", args.format("[\n  1,\n  2\n]\n").unwrap());
+
+        let args = parse(&["--canonical"]).unwrap().unwrap();
+        assert_eq!("{\"a\":1,\"b\":2}\n", args.format("{\"b\": 2, \"a\": 1}").unwrap());
+        assert_eq!("<a x=\"1\" y=\"2\"></a>\n", args.format("<a y='2' x='1'/>").unwrap());
+    }
+}
@@ -0,0 +1,525 @@
+use std::borrow::Cow;
+use std::io::{self, Read, Write};
+
+use super::{ErrorKind, FormatError, FormatOptions, LineEnding};
+
+// столько байт читается из входа за раз; столько же вывода xml копится,
+// пока не ясно, выводить элемент одной строкой или блоком
+pub(crate) const CHUNK: usize = 64 * 1024;
+
+// остаток входа короче этого разбирается только вместе со следующей частью:
+// "tru" или "<![CDA" могут оказаться началом целой лексемы
+pub(crate) const LOOKAHEAD: usize = 16;
+
+// разбор документа по частям
+pub trait Machine {
+    // машина для документа с настройками options
+    fn new(options: &FormatOptions) -> Self;
+
+    // разбирает целые лексемы из начала input и дописывает их вывод в out,
+    // возвращает длину разобранной части. Неполная лексема в конце остаётся
+    // до следующего вызова; end - частей больше не будет, и документ должен
+    // закончиться
+    fn advance(
+        &mut self,
+        input: &str,
+        end: bool,
+        options: &FormatOptions,
+        out: &mut String,
+    ) -> Result<usize, FormatError>;
+}
+
+// место во всём входе
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Position {
+    offset: usize,
+    line: usize,
+    column: usize,
+}
+
+impl Position {
+    // начало входа
+    pub(crate) fn start() -> Self {
+        Self {
+            offset: 0,
+            line: 1,
+            column: 1,
+        }
+    }
+
+    // позиция сразу за text, который начинался в этой позиции
+    pub(crate) fn advance(&mut self, text: &str) {
+        self.offset += text.len();
+        match text.rfind('\n') {
+            Some(i) => {
+                self.line += text.matches('\n').count();
+                self.column = text[i + 1..].chars().count() + 1;
+            }
+            None => self.column += text.chars().count(),
+        }
+    }
+
+    // ошибка, посчитанная от этой позиции, - в позиции во всём входе
+    pub(crate) fn shift(&self, mut error: FormatError) -> FormatError {
+        if error.line == 1 {
+            error.column += self.column - 1;
+        }
+        error.line += self.line - 1;
+        error.offset += self.offset;
+        error
+    }
+}
+
+// вход по частям: байты собираются в строку, из её начала машина разбирает
+// целые лексемы, разобранное сразу выбрасывается
+pub(crate) struct Stream<M> {
+    machine: M,
+    options: FormatOptions,
+    // ещё не разобранный вход
+    buffer: String,
+    // позиция начала buffer
+    position: Position,
+    // неполная лексема разбирается заново, только когда буфер дорастёт до
+    // этой длины, иначе длинная лексема просматривалась бы с каждой частью
+    retry_at: usize,
+    // начало символа utf-8, разрезанного границей частей
+    partial: Vec<u8>,
+    // переводы строк в конце вывода: выводятся с последующим выводом, а в
+    // конце документа - по trailing_newline
+    held: String,
+}
+
+impl<M: Machine> Stream<M> {
+    pub(crate) fn new(options: FormatOptions) -> Self {
+        Self {
+            machine: M::new(&options),
+            options,
+            buffer: String::new(),
+            position: Position::start(),
+            retry_at: 0,
+            partial: Vec::new(),
+            held: String::new(),
+        }
+    }
+
+    // вход начинается с position: пропущенное до неё учитывается в ошибках
+    pub(crate) fn after(mut self, position: Position) -> Self {
+        self.position = position;
+        self
+    }
+
+    // очередная часть входа, вывод разобранных лексем дописывается в out
+    pub(crate) fn feed(&mut self, chunk: &[u8], out: &mut String) -> Result<(), FormatError> {
+        self.decode(chunk)?;
+        if self.buffer.len() < self.retry_at {
+            return Ok(());
+        }
+        self.advance(false, out)
+    }
+
+    // конец входа: разбор остатка и перевод строки в конце по trailing_newline
+    pub(crate) fn finish(&mut self, out: &mut String) -> Result<(), FormatError> {
+        if !self.partial.is_empty() {
+            let message = "incomplete utf-8 sequence at the end of input".to_string();
+            let error = FormatError::encoding(&self.buffer, self.buffer.len(), message);
+            return Err(self.position.shift(error));
+        }
+        self.advance(true, out)?;
+
+        let held = std::mem::take(&mut self.held);
+        match self.options.trailing_newline {
+            Some(true) if held.is_empty() => out.push_str(self.options.line_ending.as_str()),
+            Some(false) => {}
+            _ => out.push_str(&held),
+        }
+        Ok(())
+    }
+
+    // ошибка чтения или записи в месте, до которого дочитан вход
+    pub(crate) fn io_error(&self, error: io::Error) -> FormatError {
+        let mut res = FormatError::at(ErrorKind::Io, &self.buffer, self.buffer.len(), None);
+        res.message = error.to_string();
+        self.position.shift(res)
+    }
+
+    // байты части дописываются в buffer, неполный последний символ ждёт
+    // следующей части
+    fn decode(&mut self, chunk: &[u8]) -> Result<(), FormatError> {
+        let bytes: Cow<[u8]> = match self.partial.is_empty() {
+            true => Cow::Borrowed(chunk),
+            false => Cow::Owned([std::mem::take(&mut self.partial).as_slice(), chunk].concat()),
+        };
+
+        match std::str::from_utf8(&bytes) {
+            Ok(text) => self.buffer.push_str(text),
+            Err(e) => {
+                let valid = e.valid_up_to();
+                // проверено from_utf8
+                self.buffer
+                    .push_str(std::str::from_utf8(&bytes[..valid]).unwrap_or_default());
+                if let Some(len) = e.error_len() {
+                    let message = format!("invalid utf-8 sequence of {} bytes", len);
+                    let error = FormatError::encoding(&self.buffer, self.buffer.len(), message);
+                    return Err(self.position.shift(error));
+                }
+                self.partial = bytes[valid..].to_vec();
+            }
+        }
+        Ok(())
+    }
+
+    fn advance(&mut self, end: bool, out: &mut String) -> Result<(), FormatError> {
+        // перевод строки берётся из входа, как только в нём встретился первый
+        if self.options.line_ending == LineEnding::Preserve {
+            if !(end || self.buffer.contains('\n') || self.buffer.len() >= CHUNK) {
+                self.retry_at = 2 * self.buffer.len();
+                return Ok(());
+            }
+            self.options = self.options.for_input(&self.buffer);
+        }
+
+        let mut res = std::mem::take(&mut self.held);
+        let consumed = self
+            .machine
+            .advance(&self.buffer, end, &self.options, &mut res)
+            .map_err(|e| self.position.shift(e))?;
+
+        let printed = res
+            .trim_end_matches(self.options.line_ending.as_str())
+            .len();
+        self.held = res[printed..].to_string();
+        out.push_str(&res[..printed]);
+
+        self.position.advance(&self.buffer[..consumed]);
+        self.buffer.drain(..consumed);
+        self.retry_at = 2 * self.buffer.len();
+        Ok(())
+    }
+}
+
+// форматирование входа, который приходит частями, например телом http-ответа:
+// feed принимает очередную часть и возвращает законченные строки вывода,
+// finish - остаток вывода. В minify вывод - одна строка и весь отдаётся в finish.
+// Формат задаёт машина разбора M, см. json::JsonFormatter и xml::XmlFormatter
+pub struct Formatter<M> {
+    stream: Stream<M>,
+    // вывод после последнего перевода строки
+    line: String,
+}
+
+impl<M: Machine> Formatter<M> {
+    pub fn new(options: impl Into<FormatOptions>) -> Self {
+        Self {
+            stream: Stream::new(options.into()),
+            line: String::new(),
+        }
+    }
+
+    // часть может заканчиваться посреди лексемы или символа utf-8
+    pub fn feed(&mut self, chunk: &[u8]) -> Result<String, FormatError> {
+        self.stream.feed(chunk, &mut self.line)?;
+        let len = self.line.rfind('\n').map_or(0, |i| i + 1);
+        let rest = self.line.split_off(len);
+        Ok(std::mem::replace(&mut self.line, rest))
+    }
+
+    // конец входа; незаконченный документ - ошибка
+    pub fn finish(mut self) -> Result<String, FormatError> {
+        self.stream.finish(&mut self.line)?;
+        Ok(self.line)
+    }
+}
+
+// форматирование всего потока: вход читается частями по CHUNK байт, вывод
+// записывается по мере разбора
+pub(crate) fn copy<M: Machine>(
+    mut stream: Stream<M>,
+    mut reader: impl Read,
+    mut writer: impl Write,
+) -> Result<(), FormatError> {
+    let mut chunk = vec![0; CHUNK];
+    let mut out = String::new();
+    loop {
+        let len = match reader.read(&mut chunk) {
+            Ok(0) => break,
+            Ok(len) => len,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(stream.io_error(e)),
+        };
+
+        stream.feed(&chunk[..len], &mut out)?;
+        writer
+            .write_all(out.as_bytes())
+            .map_err(|e| stream.io_error(e))?;
+        out.clear();
+    }
+
+    stream.finish(&mut out)?;
+    writer
+        .write_all(out.as_bytes())
+        .and_then(|_| writer.flush())
+        .map_err(|e| stream.io_error(e))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+
+    use crate::xml::{WhitespacePolicy, XmlOptions};
+    use crate::{format, format_stream, json, xml, ErrorKind, FormatOptions, LineEnding};
+
+    // вход, который читается частями не длиннее size байт
+    struct Chunks<'a> {
+        body: &'a [u8],
+        size: usize,
+    }
+
+    impl Read for Chunks<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            let len = self.size.min(buf.len()).min(self.body.len());
+            buf[..len].copy_from_slice(&self.body[..len]);
+            self.body = &self.body[len..];
+            Ok(len)
+        }
+    }
+
+    fn stream(
+        body: &str,
+        size: usize,
+        options: FormatOptions,
+    ) -> Result<String, crate::FormatError> {
+        let mut res = Vec::new();
+        let reader = Chunks {
+            body: body.as_bytes(),
+            size,
+        };
+        format_stream(reader, &mut res, options)?;
+        Ok(String::from_utf8(res).unwrap())
+    }
+
+    // поток по частям любого размера выводит то же, что format
+    fn same(body: &str, options: FormatOptions) {
+        let expected = format(body, options);
+        for size in [1, 3, 17, 4096, body.len().max(1)] {
+            match (&expected, stream(body, size, options)) {
+                (Ok(expected), Ok(res)) => assert_eq!(expected, &res, "chunk {}", size),
+                (Err(expected), Err(e)) => {
+                    assert_eq!(expected.kind(), e.kind(), "chunk {}: {}", size, e);
+                    assert_eq!(expected.offset(), e.offset(), "chunk {}: {}", size, e);
+                    assert_eq!(expected.expected(), e.expected(), "chunk {}: {}", size, e);
+                    assert_eq!((expected.line(), expected.column()), (e.line(), e.column()));
+                }
+                (expected, res) => panic!("chunk {}: {:?} != {:?}", size, expected, res),
+            }
+        }
+    }
+
+    #[test]
+    fn stream_json() {
+        let options = FormatOptions::new();
+        same(include_str!("testdata/response.json"), options);
+        same(
+            "  {\"a\": [1, 2.50, {}, [], \"x\\u0041\"], \"b\": {\"c\": null}}  ",
+            options,
+        );
+        same("[1 2,]", options);
+        same("\"text\"", options);
+        same("-12.5e3", options.trailing_newline(true));
+        same("{\"b\": 1, \"a\": TRUE}", options.minify(true));
+        same(
+            "{\"b\": 1, \"a\": [2, 1e400]}",
+            options.json(json::JsonOptions::canonical()),
+        );
+        same("[\r\n1,\r\n2]", options.line_ending(LineEnding::Preserve));
+        same("<a><!--1\n2--><b>x\r\ny</b></a>", options.line_ending(LineEnding::CrLf));
+        // число и литерал, разрезанные между частями
+        same("[12345678901234567.25, true, false]", options);
+        // пробелы после двоеточия как во входе
+        same("{\"a\":1, \"b\":    [2], \"c\":\t{\"d\":  null}}", options);
+        same("{\"a\":1, \"b\":    [2]}", options.minify(true));
+
+        same("{\"a\": 1} 2", options);
+        same("\n  {\"a\": }", options);
+        same("[1, {\"a\": 1 x}]", options);
+        same("{\"a\": [1, 2}", options);
+        same("{\"a\": \"text", options);
+        same("{\"ratio\": 1.}", options);
+        same("[1.5e-]", options);
+        same("[0, 01]", options);
+        let strict = json::JsonOptions {
+            normalize: true,
+            ..json::JsonOptions::default()
+        };
+        for body in ["{\"b\":1 \"a\":2}", "[1,]", "{\"a\": [1.5 ], \"b\": {}, }", "[[1] [2]]"] {
+            same(body, options.json(strict));
+        }
+        for body in [
+            "[\"a\\ud800b\"]",
+            "{\"\\udc00\": 1}",
+            "{\"a\": 1, \"b\": {\"a\": 2}, \"\\u0061\": 3}",
+            "[{\"a\": 1}, {\"a\": 2}]",
+        ] {
+            same(body, options.json(strict));
+            same(body, options.json(json::JsonOptions::canonical()));
+        }
+        same("[[[]]]", options.max_depth(2));
+        same("   ", options);
+    }
+
+    #[test]
+    fn stream_xml() {
+        let options = FormatOptions::new();
+        same(include_str!("testdata/request.xml"), options);
+        same(include_str!("testdata/response.xml"), options);
+        same(
+            include_str!("testdata/sirena_request.xml"),
+            options.indent(2),
+        );
+        same(
+            include_str!("testdata/sirena_response.xml"),
+            options.tabs(true),
+        );
+
+        let body = "<?xml version=\"1.0\"?>\n<!DOCTYPE a>\n<!-- c --><a x='1'>\n  <b>text</b><c/>\n  <d>one <i>two</i> three</d><![CDATA[<x>]]><?pi data?>\n</a>\n<!-- end -->\n";
+        same(body, options);
+        same(body, options.minify(true));
+        same("<a>\n  <b>x</b>\n  text\n  <c> <d/> </c>\n</a>", options.minify(true));
+        same("<p>Hello <i>big</i> <b>x</b></p>", options.minify(true));
+        same("<div>\n  <p>\n    Hello\n    <b>x</b></p>\n</div>", options.minify(true));
+        same(body, options.trailing_newline(false));
+        let reflow = XmlOptions {
+            whitespace: WhitespacePolicy::Reflow,
+            ..XmlOptions::default()
+        };
+        same(body, options.xml(reflow));
+        same(body, options.xml(reflow).minify(true));
+        same("<p>a <b>b</b> c<i>d</i>, again</p>", options.xml(reflow));
+        same("<a>only text</a>", options);
+        same("<a x='1' x='2'/>", options);
+        same("<a><b x='1'y='2'>t</b></a>", options);
+        same("<_a><:b/><c.d-1/></_a>", options);
+        same("<a><!-- a -- b --></a>", options);
+        same("<a><!-- a ---></a>", options);
+        same("<a><?xml version=\"1.0\"?></a>", options);
+        same("<!-- c --><?xml version=\"1.0\"?><a/>", options);
+        same("<a/><?xml version=\"1.0\"?>", options);
+        same("  <?xml version=\"1.0\"?><?pi?><a/>", options);
+        same("<a/>", options);
+        same("<segment seg_id=\"14\" book_time=\"19.03.2020 14:38\"/>", options);
+
+        same("<a><b>x</b ></a\n>", options);
+        same("<a>x & y</a>", options);
+        same("<a xmlns:p='u'><p:b p:x='1'/><c xmlns:q='v'><q:d/></c></a>", options);
+        same("<a><b xmlns:p='u'/><p:c/></a>", options);
+        same("<a p:x='1'/>", options);
+        same("<a><b></a>", options);
+        same("<a><b>x</c></d>", options);
+        same(&format!("<a><b>{}</b></a></b>", "x".repeat(40)), options);
+        same("<a>", options);
+        same("<a></a> x", options);
+        same("<a><b><c/></b></a>", options.max_depth(2));
+    }
+
+    #[test]
+    fn stream_large_xml() {
+        let body = include_str!("testdata/response.xml").trim();
+        let body = body.replacen("<", "<?xml version=\"1.0\"?><root>", 1);
+        let start = body.find("<root>").unwrap() + "<root>".len();
+        let items = body[start..].repeat(40);
+        let body = body[..start].to_string() + &items + "</root>";
+        same(&body, FormatOptions::new());
+
+        // текст длиннее CHUNK выводится, не дожидаясь конца элемента
+        let text = "x".repeat(3 * super::CHUNK);
+        same(&format!("<a><b>{}</b><c/></a>", text), FormatOptions::new());
+    }
+
+    // части по size байт в форматтер, каждая отдача - целые строки
+    fn push(body: &str, size: usize, mut feed: impl FnMut(&[u8]) -> String) -> String {
+        let mut res = String::new();
+        for chunk in body.as_bytes().chunks(size) {
+            let lines = feed(chunk);
+            assert!(lines.is_empty() || lines.ends_with('\n'), "{:?}", lines);
+            res += &lines;
+        }
+        res
+    }
+
+    #[test]
+    fn push_lines() {
+        let options = FormatOptions::new().indent(2);
+        let body = include_str!("testdata/response.json");
+        for size in [1, 7, body.len()] {
+            let mut formatter = json::JsonFormatter::new(options);
+            let lines = push(body, size, |chunk| formatter.feed(chunk).unwrap());
+            // строки отдаются до конца входа
+            assert!(size == body.len() || lines.len() > body.len() / 2);
+            let res = lines + &formatter.finish().unwrap();
+            assert_eq!(json::format(body, options).unwrap(), res);
+        }
+
+        let body = include_str!("testdata/sirena_response.xml");
+        for size in [1, 5, 1000] {
+            let mut formatter = xml::XmlFormatter::new(options);
+            let lines = push(body, size, |chunk| formatter.feed(chunk).unwrap());
+            let res = lines + &formatter.finish().unwrap();
+            assert_eq!(xml::format(body, options).unwrap(), res);
+        }
+
+        // без переводов строк весь вывод отдаётся в finish
+        let mut formatter = json::JsonFormatter::new(options.minify(true));
+        assert_eq!("", formatter.feed(b"{\"a\": [1, 2, 3, 4, 5, 6, 7, 8, 9]").unwrap());
+        let res = formatter.feed(b"}").unwrap() + &formatter.finish().unwrap();
+        assert_eq!("{\"a\":[1,2,3,4,5,6,7,8,9]}", res);
+    }
+
+    #[test]
+    fn push_errors() {
+        let mut formatter = xml::XmlFormatter::new(4);
+        formatter.feed(b"<a>\n  <b>\xd0").unwrap();
+        formatter.feed(b"\x96</b>").unwrap();
+        let err = formatter.finish().unwrap_err();
+        assert_eq!(ErrorKind::UnexpectedEnd, err.kind());
+        assert_eq!((2, 11), (err.line(), err.column()));
+
+        let mut formatter = json::JsonFormatter::new(4);
+        formatter.feed(b"[1, 2").unwrap();
+        let err = formatter.feed(&[b'x'; 20]).unwrap_err();
+        assert_eq!(ErrorKind::Syntax, err.kind());
+        assert_eq!(5, err.offset());
+    }
+
+    #[test]
+    fn stream_errors() {
+        let err = stream("<a>\n<b>\u{e9}", 1, FormatOptions::new()).unwrap_err();
+        assert_eq!(ErrorKind::UnexpectedEnd, err.kind());
+
+        let mut res = Vec::new();
+        let reader = Chunks {
+            body: b"[1,\n \xff]",
+            size: 2,
+        };
+        let err = json::format_stream(reader, &mut res, 4).unwrap_err();
+        assert_eq!(ErrorKind::Encoding, err.kind());
+        assert_eq!((5, 2, 2), (err.offset(), err.line(), err.column()));
+
+        let err = stream("plain text", 4, FormatOptions::new()).unwrap_err();
+        assert_eq!(ErrorKind::Syntax, err.kind());
+        same("\n  \n plain text", FormatOptions::new());
+        same(" \u{feff}[1]", FormatOptions::new());
+        same("\u{feff} \n ", FormatOptions::new());
+        // неполный литерал - не json, как у format
+        for body in ["t", " nu", "tru", "tru]", "true", "nullé", "nothing", "false ", "\u{a0}[1]"] {
+            same(body, FormatOptions::new());
+        }
+        // длинные пробелы до документа не копятся, позиции ошибок считаются от начала
+        same(&format!("{}{{\"a\" 1}}", " \n".repeat(100_000)), FormatOptions::new());
+
+        let mut res = Vec::new();
+        xml::format_stream("\u{feff}<a/>".as_bytes(), &mut res, 4).unwrap_err();
+        assert_eq!(
+            "<a/>\n",
+            stream("\u{feff}<a/>", 1, FormatOptions::new()).unwrap()
+        );
+    }
+}
@@ -1,8 +1,94 @@
 use super::{
-    And, AnyChar, AnyExcept, BoxedParser, CharSequence, Ignore, Or, Parser, ZeroOrMore,
+    format_lenient_as, furthest_of, offset_in, parse_document, And, AnyChar, AnyExcept,
+    CharSequence, Format, FormatError, FormatOptions, Or, Output, ParseError, ParseResult, Parser,
+    ZeroOrOne,
 };
+use super::stream::{self, Formatter, Machine, Stream, CHUNK, LOOKAHEAD};
+use std::borrow::Cow;
+use std::cell::{Cell, RefCell};
+use std::io::{Read, Write};
 use std::prelude::v1::*;
 
+// обработка пробелов в смешанном содержимом (текст вперемешку с элементами)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum WhitespacePolicy {
+    // элемент со смешанным содержимым выводится одной строкой как в исходнике
+    #[default]
+    Preserve,
+    // каждый узел с новой строки, пробельные текстовые узлы отбрасываются,
+    // у остальных пробелы по краям сжимаются до одного, чтобы не слипались
+    // слова и соседние элементы
+    Reflow,
+}
+
+// кавычки вокруг значений атрибутов на выходе
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum QuoteStyle {
+    // как в исходнике
+    #[default]
+    Preserve,
+    Double,
+    Single,
+}
+
+// настройки вывода xml
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct XmlOptions {
+    pub whitespace: WhitespacePolicy,
+    pub quotes: QuoteStyle,
+    // атрибуты переносятся по одному на строку, если открывающий тег шире
+    // FormatOptions::max_width символов или атрибутов больше max_attributes
+    pub max_attributes: Option<usize>,
+}
+
+// документ xml: узлы до корневого элемента (объявление xml, инструкции
+// обработки, комментарии, DOCTYPE), корневой элемент и узлы после него.
+// После разбора строки дерева - срезы входа, изменённое дерево может
+// хранить свои строки
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct XmlDocument<'a> {
+    pub prolog: Vec<XmlNode<'a>>,
+    pub root: XmlElement<'a>,
+    pub epilog: Vec<XmlNode<'a>>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct XmlElement<'a> {
+    pub name: Cow<'a, str>,
+    pub attributes: Vec<XmlAttribute<'a>>,
+    pub children: Vec<XmlNode<'a>>,
+    // записан как <name/>
+    pub self_closing: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct XmlAttribute<'a> {
+    pub name: Cow<'a, str>,
+    // значение между кавычками как во входе, ссылки на сущности не раскрыты
+    pub value: Cow<'a, str>,
+    // '"' или '\''
+    pub quote: char,
+}
+
+// узел среди детей элемента. Текст хранится как во входе, вместе с пробелами
+// между элементами
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum XmlNode<'a> {
+    Element(XmlElement<'a>),
+    Text(Cow<'a, str>),
+    // содержимое между <!-- и -->
+    Comment(Cow<'a, str>),
+    // содержимое между <![CDATA[ и ]]>
+    CData(Cow<'a, str>),
+    // data - всё после имени цели, вместе с пробелами перед ним
+    ProcessingInstruction {
+        target: Cow<'a, str>,
+        data: Cow<'a, str>,
+    },
+    // объявление <!DOCTYPE ...> целиком
+    DocType(Cow<'a, str>),
+}
+
 // имя элемента xml
 struct ElementName {}
 
@@ -12,372 +98,1628 @@ impl ElementName {
     }
 }
 
-impl Parser for ElementName {
-    fn parse<'a>(&self, in_string: &'a str) -> (Result<String, ()>, &'a str) {
-        if in_string.is_empty() {
-            return (Err(()), in_string);
+impl<'a> Parser<'a> for ElementName {
+    fn parse(&self, in_string: &'a str) -> ParseResult<'a> {
+        if !in_string.starts_with(name_start_char) {
+            return ParseResult::err(ParseError::new(in_string, "name"), in_string);
         }
 
-        let mut iter = in_string.chars();
-        let first = iter.next().unwrap();
-        if !first.is_alphabetic() {
-            return (Err(()), in_string);
+        let len = in_string.find(|ch| !name_char(ch)).unwrap_or(in_string.len());
+        ParseResult::ok(&in_string[..len], &in_string[len..])
+    }
+}
+
+// первый символ имени по продукции NameStartChar из XML 1.0
+fn name_start_char(ch: char) -> bool {
+    matches!(ch,
+        ':' | 'A'..='Z' | '_' | 'a'..='z' | '\u{C0}'..='\u{D6}' | '\u{D8}'..='\u{F6}'
+        | '\u{F8}'..='\u{2FF}' | '\u{370}'..='\u{37D}' | '\u{37F}'..='\u{1FFF}'
+        | '\u{200C}'..='\u{200D}' | '\u{2070}'..='\u{218F}' | '\u{2C00}'..='\u{2FEF}'
+        | '\u{3001}'..='\u{D7FF}' | '\u{F900}'..='\u{FDCF}' | '\u{FDF0}'..='\u{FFFD}'
+        | '\u{10000}'..='\u{EFFFF}')
+}
+
+// остальные символы имени, продукция NameChar
+fn name_char(ch: char) -> bool {
+    name_start_char(ch)
+        || matches!(ch,
+            '-' | '.' | '0'..='9' | '\u{B7}' | '\u{300}'..='\u{36F}' | '\u{203F}'..='\u{2040}')
+}
+
+// открывающий тег <name attrs> или пустой элемент <name attrs/>, дети не заполнены
+struct StartTag {
+    name: ElementName,
+    attributes: AttributeList,
+}
+
+impl StartTag {
+    fn new() -> Self {
+        Self {
+            name: ElementName::new(),
+            attributes: AttributeList::new(),
         }
+    }
+}
 
-        let mut res = first.to_string();
-        while let Some(ch) = iter.next() {
-            if ch.is_alphanumeric() || ch == ':' || ch == '_' || ch == '-' {
-                res.push(ch);
-            } else {
-                break;
+impl<'a> Parser<'a, XmlElement<'a>> for StartTag {
+    fn parse(&self, in_string: &'a str) -> ParseResult<'a, XmlElement<'a>> {
+        let Some(rest) = in_string.strip_prefix('<') else {
+            return ParseResult::err(ParseError::new(in_string, "`<`"), in_string);
+        };
+
+        let (name, rest) = match self.name.parse(rest) {
+            ParseResult(Ok(name), rest, _) => (name, rest),
+            ParseResult(Err(e), _, _) => return ParseResult::err(e, in_string),
+        };
+
+        let (attributes, rest, furthest) = match self.attributes.attributes(rest) {
+            ParseResult(Ok(attributes), rest, f) => (attributes, rest, f),
+            ParseResult(Err(e), _, _) => return ParseResult::err(e, in_string),
+        };
+
+        let (self_closing, rest) = match (rest.strip_prefix("/>"), rest.strip_prefix('>')) {
+            (Some(rest), _) => (true, rest),
+            (_, Some(rest)) => (false, rest),
+            _ => {
+                let empty = ParseError::new(rest, "`/>`");
+                let error = ParseError::new(rest, "`>`").furthest(Some(empty));
+                return ParseResult::err(error.furthest(furthest), in_string);
             }
+        };
+
+        let element = XmlElement {
+            name: Cow::Borrowed(name),
+            attributes,
+            children: Vec::new(),
+            self_closing,
+        };
+        ParseResult(Ok(element), rest, furthest)
+    }
+}
+
+// результат парсеров узлов для входа с любым временем жизни
+struct Nodes;
+
+impl Output for Nodes {
+    type Of<'a> = XmlNode<'a>;
+}
+
+// текст между тегами - срез входа
+impl XmlNode<'_> {
+    fn text(s: &str) -> XmlNode<'_> {
+        XmlNode::Text(Cow::Borrowed(s))
+    }
+}
+
+// текст между тегами, ссылки на сущности в нём проверяются как в значениях атрибутов
+struct Text {
+    parser: AnyExcept,
+}
+
+impl Text {
+    fn new() -> Self {
+        Self {
+            parser: AnyExcept::new("<"),
         }
+    }
+}
 
-        let parsed_len = res.len();
-        return (Ok(res), &in_string[parsed_len..]);
+impl<'a> Parser<'a, XmlNode<'a>> for Text {
+    fn parse(&self, in_string: &'a str) -> ParseResult<'a, XmlNode<'a>> {
+        match self.parser.parse(in_string) {
+            ParseResult(Ok(text), rest, f) => match invalid_value_position(text) {
+                Some(i) => {
+                    let error = ParseError::new(&in_string[i..], "entity reference");
+                    ParseResult::err(error, in_string)
+                }
+                None => ParseResult(Ok(XmlNode::text(text)), rest, f),
+            },
+            ParseResult(Err(e), _, _) => ParseResult::err(e, in_string),
+        }
     }
 }
 
-// целый элемент xml, без детей и текста
-struct ElementFull<'a, M> {
-    level: usize,
-    mapper: M,
-    parser: And<'a>,
+// элемент целиком, вместе с детьми. Парсеры строятся один раз, вложенные
+// элементы разбираются этим же Element. Элементы глубже max_depth - ошибка,
+// depth - вложенность разбираемого сейчас элемента
+struct Element {
+    start: StartTag,
+    // дети, кроме вложенных элементов
+    child: Or<Nodes>,
+    close: ElementClose,
+    max_depth: usize,
+    depth: Cell<usize>,
+    // префиксы пространств имён, объявленные разбираемым элементом и его предками
+    scope: RefCell<Vec<String>>,
 }
 
-impl<'a, M> ElementFull<'a, M> {
-    fn new(level: usize, mapper: M) -> Self {
-        let mut parser = And::new();
-        parser.add_parser(CharSequence::new("<".to_string()));
-        parser.add_parser(ElementName::new());
-        parser.add_parser(AttributeList::new());
-        parser.add_parser(BoxedParser::new(CharSequence::new("/>".to_string())));
+impl Element {
+    fn new(max_depth: usize) -> Self {
+        let mut child: Or<Nodes> = Or::new();
+        child.add_parser(Comment::new());
+        child.add_parser(CData::new());
+        child.add_parser(ElementXml::new(false));
+        child.add_parser(Text::new());
 
         Self {
-            level,
-            mapper,
-            parser,
+            start: StartTag::new(),
+            child,
+            close: ElementClose::new(),
+            max_depth,
+            depth: Cell::new(0),
+            scope: RefCell::new(Vec::new()),
+        }
+    }
+
+    // вложенный элемент или другой узел; ошибки всех вариантов объединяются,
+    // как в Or
+    fn node<'a>(&self, in_string: &'a str) -> ParseResult<'a, XmlNode<'a>> {
+        let element = match Parser::<XmlElement>::parse(self, in_string) {
+            ParseResult(Ok(element), rest, f) => {
+                return ParseResult(Ok(XmlNode::Element(element)), rest, f)
+            }
+            ParseResult(Err(e), _, _) => e,
+        };
+
+        match self.child.parse(in_string) {
+            ParseResult(Ok(node), rest, f) => {
+                ParseResult(Ok(node), rest, furthest_of(f, Some(element)))
+            }
+            ParseResult(Err(e), _, _) => ParseResult::err(e.furthest(Some(element)), in_string),
         }
     }
 }
 
-impl<'a, M> Parser for ElementFull<'a, M>
-where
-    M: for<'c> Fn(&'c str, usize) -> String + 'a + Copy,
-{
-    fn parse<'b>(&self, in_string: &'b str) -> (Result<String, ()>, &'b str) {
-        let res = self.parser.parse(in_string);
-        return if let Ok(r) = res.0 {
-            let r1 = (self.mapper)(&r, self.level);
-            (Ok(r1), res.1)
-        } else {
-            res
+impl<'a> Parser<'a, XmlElement<'a>> for Element {
+    fn parse(&self, in_string: &'a str) -> ParseResult<'a, XmlElement<'a>> {
+        let (mut element, mut rest, mut furthest) = match self.start.parse(in_string) {
+            ParseResult(Ok(element), rest, furthest) => (element, rest, furthest),
+            ParseResult(Err(e), _, _) => return ParseResult::err(e, in_string),
+        };
+        let depth = self.depth.get();
+        if depth >= self.max_depth {
+            return ParseResult::err(ParseError::too_deep(in_string, self.max_depth), in_string);
+        }
+
+        // объявления элемента действуют до его конца
+        let outer = self.scope.borrow().len();
+        let undeclared = undeclared_prefix(&element, &mut self.scope.borrow_mut());
+        if let Some(name) = undeclared {
+            self.scope.borrow_mut().truncate(outer);
+            let error = ParseError::new(&in_string[offset_in(in_string, name)..], PREFIX);
+            return ParseResult::err(error, in_string);
+        }
+        if element.self_closing {
+            self.scope.borrow_mut().truncate(outer);
+            return ParseResult(Ok(element), rest, furthest);
+        }
+
+        self.depth.set(depth + 1);
+        let stop = loop {
+            match self.node(rest) {
+                ParseResult(Ok(node), r, f) => {
+                    element.children.push(node);
+                    rest = r;
+                    furthest = furthest_of(f, furthest);
+                }
+                ParseResult(Err(e), _, _) => break e.furthest(furthest),
+            }
         };
+        self.depth.set(depth);
+        self.scope.borrow_mut().truncate(outer);
+
+        match self.close.parse(rest) {
+            // другое имя в закрывающем теге - ошибка, а не исправление входа
+            ParseResult(Ok(close), _, _) if ElementClose::name(close) != element.name => {
+                let error = ParseError::new(rest, format!("`</{}>`", element.name));
+                ParseResult::err(error, in_string)
+            }
+            ParseResult(Ok(_), rest, f) => {
+                ParseResult(Ok(element), rest, furthest_of(f, Some(stop)))
+            }
+            ParseResult(Err(e), _, _) => ParseResult::err(e.furthest(Some(stop)), in_string),
+        }
     }
 }
 
-// начало элемента
-struct ElementOpen<'a, M> {
-    level: usize,
-    mapper: M,
-    parser: And<'a>,
+impl<'a> Parser<'a, XmlNode<'a>> for Element {
+    fn parse(&self, in_string: &'a str) -> ParseResult<'a, XmlNode<'a>> {
+        let res: ParseResult<XmlElement> = self.parse(in_string);
+        res.map(XmlNode::Element)
+    }
+}
+
+// ожидаемое на месте имени с необъявленным префиксом
+const PREFIX: &str = "declared namespace prefix";
+
+// префиксы, объявленные атрибутами xmlns:prefix элемента, в конец scope
+fn declare(element: &XmlElement, scope: &mut Vec<String>) {
+    for attribute in &element.attributes {
+        if let Some(prefix) = attribute.name.strip_prefix("xmlns:") {
+            scope.push(prefix.to_string());
+        }
+    }
 }
 
-impl<'a, M> ElementOpen<'a, M>
-where
-    M: for<'c> Fn(&'c str, usize) -> String + 'a + Copy,
-{
-    fn new(level: usize, mapper: M) -> Self {
+// имя элемента или атрибута, префикс которого не объявлен ни самим элементом,
+// ни в scope - предками. Объявления элемента добавляются в scope, префиксы
+// xml и xmlns объявлены всегда
+fn undeclared_prefix<'e>(element: &'e XmlElement, scope: &mut Vec<String>) -> Option<&'e str> {
+    declare(element, scope);
+    let names = element.attributes.iter().map(|attribute| attribute.name.as_ref());
+    std::iter::once(element.name.as_ref()).chain(names).find(|name| {
+        match name.split_once(':') {
+            Some(("xml" | "xmlns", _)) => false,
+            Some((prefix, local)) if !prefix.is_empty() && !local.is_empty() => {
+                !scope.iter().any(|declared| declared == prefix)
+            }
+            _ => false,
+        }
+    })
+}
+
+// завершение элемента </name>, перед > допустимы пробелы. Имя с открывающим
+// тегом сверяет Element
+struct ElementClose {
+    parser: And,
+}
+
+impl ElementClose {
+    fn new() -> Self {
         let mut parser = And::new();
-        parser.add_parser(CharSequence::new("<".to_string()));
+        parser.add_parser(CharSequence::new("</"));
         parser.add_parser(ElementName::new());
-        parser.add_parser(AttributeList::new());
-        parser.add_parser(CharSequence::new(">".to_string()));
+        parser.add_parser(ZeroOrOne::new(AnyChar::new(|ch: char| {
+            matches!(ch, ' ' | '\t' | '\r' | '\n')
+        })));
+        parser.add_parser(CharSequence::new(">"));
 
-        Self {
-            level,
-            mapper,
-            parser,
-        }
+        Self { parser }
+    }
+
+    // имя из разобранного закрывающего тега
+    fn name(close: &str) -> &str {
+        close["</".len()..close.len() - ">".len()].trim_end()
+    }
+}
+
+impl<'a> Parser<'a> for ElementClose {
+    fn parse(&self, in_string: &'a str) -> ParseResult<'a> {
+        self.parser.parse(in_string)
+    }
+}
+
+// комментарий <!-- ... -->, внутри которого нет `--`
+struct Comment {
+    parser: And,
+}
+
+impl Comment {
+    fn new() -> Self {
+        let mut parser = And::new();
+        parser.add_parser(CharSequence::new("<!--"));
+        parser.add_parser(ZeroOrOne::new(AnyExcept::new("-->")));
+        parser.add_parser(CharSequence::new("-->"));
+
+        Self { parser }
     }
 }
 
-impl<'a, M> Parser for ElementOpen<'a, M>
-where
-    M: for<'c> Fn(&'c str, usize) -> String + 'a + Copy,
-{
-    fn parse<'b>(&self, in_string: &'b str) -> (Result<String, ()>, &'b str) {
+impl<'a> Parser<'a, XmlNode<'a>> for Comment {
+    fn parse(&self, in_string: &'a str) -> ParseResult<'a, XmlNode<'a>> {
         let res = self.parser.parse(in_string);
-        return if let Ok(r) = res.0 {
-            let r1 = (self.mapper)(&r, self.level);
-            (Ok(r1), res.1)
-        } else {
-            res
-        };
+        if let ParseResult(Ok(r), _, _) = &res {
+            // первое `--` после начала должно быть концом комментария
+            let body = &r["<!--".len()..r.len() - ">".len()];
+            match body.find("--") {
+                Some(i) if i + "--".len() < body.len() => {
+                    let at = &in_string["<!--".len() + i..];
+                    return ParseResult::err(ParseError::new(at, "`-->`"), in_string);
+                }
+                _ => {}
+            }
+        }
+        res.map(|r| XmlNode::Comment(Cow::Borrowed(&r["<!--".len()..r.len() - "-->".len()])))
     }
 }
 
-// завершение элемента
-struct ElementClose<'a, M> {
-    level: usize,
-    mapper: M,
-    parser: And<'a>,
+// секция <![CDATA[ ... ]]>, содержимое выводится байт в байт
+struct CData {
+    parser: And,
+}
+
+impl CData {
+    fn new() -> Self {
+        let mut parser = And::new();
+        parser.add_parser(CharSequence::new("<![CDATA["));
+        parser.add_parser(ZeroOrOne::new(AnyExcept::new("]]>")));
+        parser.add_parser(CharSequence::new("]]>"));
+
+        Self { parser }
+    }
+}
+
+impl<'a> Parser<'a, XmlNode<'a>> for CData {
+    fn parse(&self, in_string: &'a str) -> ParseResult<'a, XmlNode<'a>> {
+        self.parser
+            .parse(in_string)
+            .map(|r| XmlNode::CData(Cow::Borrowed(&r["<![CDATA[".len()..r.len() - "]]>".len()])))
+    }
+}
+
+// объявление <!DOCTYPE ...>, внутреннее подмножество [...] выводится как есть
+struct DocType {}
+
+impl DocType {
+    fn new() -> Self {
+        Self {}
+    }
+}
+
+impl<'a> Parser<'a, XmlNode<'a>> for DocType {
+    fn parse(&self, in_string: &'a str) -> ParseResult<'a, XmlNode<'a>> {
+        let start = "<!DOCTYPE";
+        if !in_string.starts_with(start) {
+            let error = ParseError::new(in_string, format!("`{}`", start));
+            return ParseResult::err(error, in_string);
+        }
+
+        // '>' внутри кавычек и квадратных скобок не закрывает объявление
+        let mut quote = None;
+        let mut depth = 0;
+        for (i, ch) in in_string.char_indices().skip(start.len()) {
+            match (quote, ch) {
+                (Some(q), _) if q == ch => quote = None,
+                (Some(_), _) => {}
+                (None, '"' | '\'') => quote = Some(ch),
+                (None, '[') => depth += 1,
+                (None, ']') => depth -= 1,
+                (None, '>') if depth <= 0 => {
+                    let end = i + 1;
+                    let res = XmlNode::DocType(Cow::Borrowed(&in_string[..end]));
+                    return ParseResult::ok(res, &in_string[end..]);
+                }
+                _ => {}
+            }
+        }
+
+        let end = &in_string[in_string.len()..];
+        ParseResult::err(ParseError::new(end, "`>`"), in_string)
+    }
 }
 
-impl<'a, M> ElementClose<'a, M>
-where
-    M: for<'c> Fn(&'c str, usize) -> String + 'a + Copy,
-{
-    fn new(level: usize, mapper: M) -> Self {
+// инструкция обработки <?target ...?>; объявление xml (цель xml в любом
+// регистре) допускается только при declaration - в начале документа
+struct ElementXml {
+    parser: And,
+    declaration: bool,
+}
+
+impl ElementXml {
+    fn new(declaration: bool) -> Self {
         let mut parser = And::new();
-        parser.add_parser(CharSequence::new("</".to_string()));
+        parser.add_parser(CharSequence::new("<?"));
         parser.add_parser(ElementName::new());
-        parser.add_parser(CharSequence::new(">".to_string()));
+        parser.add_parser(ZeroOrOne::new(AnyExcept::new("?>")));
+        parser.add_parser(CharSequence::new("?>"));
 
         Self {
             parser,
-            level,
-            mapper,
+            declaration,
         }
     }
 }
 
-impl<'a, M> Parser for ElementClose<'a, M>
-where
-    M: for<'c> Fn(&'c str, usize) -> String + 'a + Copy,
-{
-    fn parse<'b>(&self, in_string: &'b str) -> (Result<String, ()>, &'b str) {
-        let res = self.parser.parse(in_string);
-        return if let Ok(r) = res.0 {
-            let r1 = (self.mapper)(&r, self.level);
-            (Ok(r1), res.1)
-        } else {
-            res
-        };
+impl<'a> Parser<'a, XmlNode<'a>> for ElementXml {
+    fn parse(&self, in_string: &'a str) -> ParseResult<'a, XmlNode<'a>> {
+        let res = self.parser.parse(in_string).map(|r| {
+            let inner = &r["<?".len()..r.len() - "?>".len()];
+            let data = ElementName::new().parse(inner).1;
+            XmlNode::ProcessingInstruction {
+                target: Cow::Borrowed(&inner[..inner.len() - data.len()]),
+                data: Cow::Borrowed(data),
+            }
+        });
+
+        match res {
+            ParseResult(Ok(XmlNode::ProcessingInstruction { target, .. }), _, _)
+                if !self.declaration && target.eq_ignore_ascii_case("xml") =>
+            {
+                let at = &in_string["<?".len()..];
+                ParseResult::err(ParseError::new(at, "target other than `xml`"), in_string)
+            }
+            res => res,
+        }
     }
 }
 
-struct ElementWithText<'a, M> {
-    parser: And<'a>,
-    mapper: M,
+// тег одной строкой либо каждый атрибут на своей строке под именем элемента
+fn wrap_attributes(
+    name: &str,
+    attributes: &[String],
+    end: &str,
     level: usize,
-}
+    options: &FormatOptions,
+) -> String {
+    let line = format!("<{}{}{}", name, attributes.concat(), end);
+
+    let column = options.indentation_width(level);
+    let too_wide = options
+        .max_width
+        .is_some_and(|width| column + line.chars().count() > width);
+    let too_many = options
+        .xml
+        .max_attributes
+        .is_some_and(|amount| attributes.len() > amount);
+    if options.minify || attributes.is_empty() || !(too_wide || too_many) {
+        return line;
+    }
 
-impl<'a, M> ElementWithText<'a, M> {
-    fn new(level: usize, mapper: M) -> Self
-    where
-        M: for<'c> Fn(&'c str, usize) -> String + 'a + Copy,
-    {
-        let mut parser = And::new();
+    let align = options.indentation(level) + " ";
+    let mut res = format!("<{}", name);
+    for attribute in attributes {
+        res += options.line_ending.as_str();
+        res += &align;
+        res += attribute.trim_start();
+    }
 
-        let no_map = |parsed: &str, _: usize| parsed.to_string();
+    res + end
+}
 
-        parser.add_parser(ElementOpen::new(level, no_map.clone()));
-        parser.add_parser(ZeroOrMore::new(AnyExcept::new("<".to_string())));
-        parser.add_parser(ElementClose::new(0, no_map.clone()));
+// атрибут name="value" или name='value', вокруг = допустимы пробелы
+struct Attribute {
+    name: ElementName,
+}
 
+impl Attribute {
+    fn new() -> Self {
         Self {
-            parser,
-            mapper,
-            level,
+            name: ElementName::new(),
         }
     }
 }
 
-impl<'a, M> Parser for ElementWithText<'a, M>
-where
-    M: for<'c> Fn(&'c str, usize) -> String + 'a + Copy,
-{
-    fn parse<'b>(&self, in_string: &'b str) -> (Result<String, ()>, &'b str) {
-        let res = self.parser.parse(in_string);
-        return if let Ok(r) = res.0 {
-            let r1 = (self.mapper)(&r, self.level);
-            (Ok(r1), res.1)
-        } else {
-            res
+impl<'a> Parser<'a, XmlAttribute<'a>> for Attribute {
+    fn parse(&self, in_string: &'a str) -> ParseResult<'a, XmlAttribute<'a>> {
+        let (name, rest) = match self.name.parse(in_string) {
+            ParseResult(Ok(name), rest, _) => (name, rest),
+            ParseResult(Err(e), _, _) => return ParseResult::err(e, in_string),
+        };
+
+        let rest = rest.trim_start();
+        let Some(rest) = rest.strip_prefix('=') else {
+            return ParseResult::err(ParseError::new(rest, "`=`"), in_string);
+        };
+
+        let rest = rest.trim_start();
+        let quote = match rest.chars().next() {
+            Some(ch @ ('"' | '\'')) => ch,
+            _ => {
+                let double = ParseError::new(rest, "`\"`");
+                let error = ParseError::new(rest, "`'`").furthest(Some(double));
+                return ParseResult::err(error, in_string);
+            }
+        };
+
+        let value_start = &rest[1..];
+        let Some(len) = value_start.find(quote) else {
+            let end = &rest[rest.len()..];
+            return ParseResult::err(ParseError::new(end, format!("`{}`", quote)), in_string);
         };
+        let value = &value_start[..len];
+
+        if let Some(position) = invalid_value_position(value) {
+            let at = &value_start[position..];
+            let expected = match at.starts_with('&') {
+                true => Cow::Borrowed("entity reference"),
+                false => Cow::Owned(format!("`{}`", quote)),
+            };
+            return ParseResult::err(ParseError::new(at, expected), in_string);
+        }
+
+        let attribute = XmlAttribute {
+            name: Cow::Borrowed(name),
+            value: Cow::Borrowed(value),
+            quote,
+        };
+        ParseResult::ok(attribute, &value_start[len + 1..])
     }
 }
 
-struct ElementsSet<'a> {
-    parser: And<'a>,
+// позиция '<' или некорректной ссылки на сущность в значении атрибута
+fn invalid_value_position(value: &str) -> Option<usize> {
+    for (i, ch) in value.char_indices() {
+        match ch {
+            '<' => return Some(i),
+            '&' if entity_len(&value[i..]).is_none() => return Some(i),
+            _ => {}
+        }
+    }
+
+    None
 }
 
-impl<'a> ElementsSet<'a> {
-    fn new<M>(level: usize, mapper: M) -> Self
-    where
-        M: for<'c> Fn(&'c str, usize) -> String + 'a + Copy,
-    {
-        let mut parser = And::new();
-        let mut or = Or::new();
-        or.add_parser(ElementWithText::new(level, mapper.clone()));
-        or.add_parser(ElementFull::new(level, mapper.clone()));
-        or.add_parser(ElementAny::new(level, mapper.clone()));
-        parser.add_parser(or);
+// длина ссылки в начале строки: &name; &#123; или &#x1F;. Поиск ';' не идёт
+// дальше символов, допустимых в ссылке, чтобы строка из одних '&' не
+// просматривалась до конца от каждого из них
+fn entity_len(s: &str) -> Option<usize> {
+    let end = s[1..]
+        .find(|ch: char| !(name_char(ch) || ch == '#'))
+        .map(|i| i + 1)
+        .filter(|&i| s[i..].starts_with(';'))?;
+    let body = &s[1..end];
+
+    let code = |digits: &str, radix: u32| {
+        let valid = !digits.is_empty() && digits.chars().all(|ch| ch.is_digit(radix));
+        valid
+            && u32::from_str_radix(digits, radix)
+                .ok()
+                .and_then(char::from_u32)
+                .is_some_and(|ch| ch != '\0')
+    };
 
-        let is_space = |ch: char| ch.is_whitespace();
-        parser.add_parser(Ignore::new(ZeroOrMore::new(AnyChar::new(is_space))));
+    let valid = if let Some(hex) = body.strip_prefix("#x") {
+        code(hex, 16)
+    } else if let Some(dec) = body.strip_prefix('#') {
+        code(dec, 10)
+    } else {
+        let mut chars = body.chars();
+        chars.next().is_some_and(name_start_char) && chars.all(name_char)
+    };
 
-        Self { parser }
+    valid.then_some(end + 1)
+}
+
+struct AttributeList {
+    attribute: Attribute,
+}
+
+impl AttributeList {
+    fn new() -> Self {
+        Self {
+            attribute: Attribute::new(),
+        }
+    }
+
+    // атрибуты и остаток без пробелов в начале. Атрибуты разделяются
+    // пробелами, повтор имени - ошибка
+    fn attributes<'a>(&self, in_string: &'a str) -> ParseResult<'a, Vec<XmlAttribute<'a>>> {
+        let mut res: Vec<XmlAttribute> = Vec::new();
+        let mut rest = in_string;
+        let mut furthest = None;
+
+        let stop = loop {
+            let start = rest.trim_start();
+            match self.attribute.parse(start) {
+                ParseResult(Ok(_), _, _) if !res.is_empty() && start.len() == rest.len() => {
+                    return ParseResult::err(ParseError::new(rest, "whitespace"), in_string);
+                }
+                ParseResult(Ok(parsed), _, _) if res.iter().any(|a| a.name == parsed.name) => {
+                    let expected = format!("attribute other than `{}`", parsed.name);
+                    return ParseResult::err(ParseError::new(start, expected), in_string);
+                }
+                ParseResult(Ok(parsed), r, f) => {
+                    res.push(parsed);
+                    rest = r;
+                    furthest = furthest_of(f, furthest);
+                }
+                ParseResult(Err(e), _, _) => break e.furthest(furthest),
+            }
+        };
+
+        ParseResult(Ok(res), rest.trim_start(), Some(stop))
     }
 }
 
-impl<'a> Parser for ElementsSet<'a> {
-    fn parse<'b>(&self, in_string: &'b str) -> (Result<String, ()>, &'b str) {
-        self.parser.parse(in_string)
+const XML_NAMESPACE: &str = "http://www.w3.org/XML/1998/namespace";
+
+// элемент в канонической форме (Canonical XML 1.0) в конец res: пустые элементы
+// раскрыты, атрибуты в двойных кавычках и отсортированы, лишние объявления
+// пространств имён убраны, текст и пробелы внутри элемента выводятся как в
+// исходнике. namespaces - пространства имён, объявленные выведенными предками:
+// (префикс, uri)
+fn write_canonical_element(
+    res: &mut String,
+    element: &XmlElement,
+    namespaces: &[(String, String)],
+    comments: bool,
+) {
+    let mut declarations = Vec::new();
+    let mut attrs = Vec::new();
+    for attribute in &element.attributes {
+        let name = attribute.name.as_ref();
+        let value = canonical_chars(&attribute.value, true);
+        match name.strip_prefix("xmlns") {
+            Some("") => declarations.push((String::new(), value)),
+            Some(prefix) if prefix.starts_with(':') => {
+                declarations.push((prefix[1..].to_string(), value))
+            }
+            _ => attrs.push((name, value)),
+        }
+    }
+
+    // объявление лишнее, если выведенный предок уже объявил то же самое;
+    // xmlns="" лишнее, пока пространство по умолчанию не задано
+    let mut scope = Cow::Borrowed(namespaces);
+    let mut rendered = Vec::new();
+    for (prefix, uri) in declarations {
+        let inherited = scope.iter().find(|(p, _)| *p == prefix);
+        if inherited.map_or("", |(_, u)| u.as_str()) != uri {
+            let scope = scope.to_mut();
+            scope.retain(|(p, _)| *p != prefix);
+            scope.push((prefix.clone(), uri.clone()));
+            rendered.push((prefix, uri));
+        }
+    }
+    rendered.sort();
+    attrs.sort_by(|a, b| qualified_name(a.0, &scope).cmp(&qualified_name(b.0, &scope)));
+
+    *res += &format!("<{}", element.name);
+    for (prefix, uri) in &rendered {
+        match prefix.is_empty() {
+            true => *res += &format!(" xmlns=\"{}\"", uri),
+            false => *res += &format!(" xmlns:{}=\"{}\"", prefix, uri),
+        }
+    }
+    for (name, value) in &attrs {
+        *res += &format!(" {}=\"{}\"", name, value);
+    }
+    res.push('>');
+
+    for child in &element.children {
+        match child {
+            XmlNode::Element(child) => write_canonical_element(res, child, &scope, comments),
+            XmlNode::Text(text) => *res += &canonical_chars(text, false),
+            XmlNode::CData(content) => {
+                *res += &normalize_newlines(content)
+                    .replace('&', "&amp;")
+                    .replace('<', "&lt;")
+                    .replace('>', "&gt;")
+            }
+            node => write_canonical_misc(res, node, comments),
+        }
     }
+    *res += &format!("</{}>", element.name);
 }
 
-struct ElementAny<M> {
-    level: usize,
-    mapper: M,
+// (uri пространства имён, локальное имя) - ключ сортировки атрибутов,
+// у атрибутов без префикса пространства имён нет
+fn qualified_name<'n>(name: &'n str, scope: &'n [(String, String)]) -> (&'n str, &'n str) {
+    match name.split_once(':') {
+        Some(("xml", local)) => (XML_NAMESPACE, local),
+        Some((prefix, local)) => {
+            let uri = scope.iter().find(|(p, _)| p == prefix);
+            (uri.map_or("", |(_, uri)| uri.as_str()), local)
+        }
+        None => ("", name),
+    }
+}
+
+fn normalize_newlines(s: &str) -> String {
+    s.replace("\r\n", "\n").replace('\r', "\n")
 }
 
-impl<'a, M> ElementAny<M> {
-    fn new(level: usize, mapper: M) -> Self {
-        Self { level, mapper }
+// текст или значение атрибута в канонической форме: переводы строк приведены
+// к \n (в атрибуте пробельные символы заменены пробелом), ссылки на символы
+// раскрыты, спецсимволы экранированы. Ссылки на сущности из DTD остаются как
+// есть, некорректных ссылок после разбора нет
+fn canonical_chars(value: &str, attribute: bool) -> String {
+    let escape = |ch: char, res: &mut String| {
+        let escaped = match (ch, attribute) {
+            ('&', _) => "&amp;",
+            ('<', _) => "&lt;",
+            ('>', false) => "&gt;",
+            ('"', true) => "&quot;",
+            ('\t', true) => "&#x9;",
+            ('\n', true) => "&#xA;",
+            ('\r', _) => "&#xD;",
+            _ => {
+                res.push(ch);
+                return;
+            }
+        };
+        res.push_str(escaped);
+    };
+
+    let mut res = String::with_capacity(value.len());
+    let mut chars = value.char_indices().peekable();
+    while let Some((i, ch)) = chars.next() {
+        match ch {
+            '&' => {
+                let Some(len) = entity_len(&value[i..]) else {
+                    escape(ch, &mut res);
+                    continue;
+                };
+                let reference = &value[i..i + len];
+                match resolve_reference(&reference[1..len - 1]) {
+                    Some(ch) => escape(ch, &mut res),
+                    None => res.push_str(reference),
+                }
+                while chars.next_if(|&(j, _)| j < i + len).is_some() {}
+            }
+            '\r' | '\n' | '\t' => {
+                if ch == '\r' {
+                    chars.next_if(|&(_, next)| next == '\n');
+                }
+                match (attribute, ch) {
+                    (true, _) => res.push(' '),
+                    (false, '\t') => res.push('\t'),
+                    (false, _) => res.push('\n'),
+                }
+            }
+            _ => escape(ch, &mut res),
+        }
     }
+
+    res
 }
 
-impl<'a, M> Parser for ElementAny<M>
-where
-    M: for<'c> Fn(&'c str, usize) -> String + 'a + Copy,
-{
-    fn parse<'b>(&self, in_string: &'b str) -> (Result<String, ()>, &'b str) {
-        let mut parser = And::new();
+// символ по ссылке без & и ;, None - сущность из DTD
+fn resolve_reference(name: &str) -> Option<char> {
+    let code = match name {
+        "lt" => return Some('<'),
+        "gt" => return Some('>'),
+        "amp" => return Some('&'),
+        "quot" => return Some('"'),
+        "apos" => return Some('\''),
+        _ => match name.strip_prefix("#x") {
+            Some(hex) => u32::from_str_radix(hex, 16).ok(),
+            None => name.strip_prefix('#').and_then(|dec| dec.parse().ok()),
+        },
+    };
+
+    code.and_then(char::from_u32)
+}
 
-        let is_space = |ch: char| ch.is_ascii_whitespace();
+// документ в канонической форме: без объявления xml и DOCTYPE, узлы вне
+// корневого элемента отделены от него переводом строки
+fn print_canonical(document: &XmlDocument, comments: bool) -> String {
+    let mut res = String::new();
+    for node in &document.prolog {
+        let start = res.len();
+        write_canonical_misc(&mut res, node, comments);
+        if res.len() > start {
+            res.push('\n');
+        }
+    }
 
-        parser.add_parser(ElementOpen::new(self.level, self.mapper.clone()));
-        parser.add_parser(Ignore::new(ZeroOrMore::new(AnyChar::new(is_space))));
-        parser.add_parser(ZeroOrMore::new(ElementsSet::new(
-            self.level + 1,
-            self.mapper.clone(),
-        )));
-        parser.add_parser(Ignore::new(ZeroOrMore::new(AnyChar::new(is_space))));
-        parser.add_parser(ElementClose::new(self.level, self.mapper.clone()));
+    write_canonical_element(&mut res, &document.root, &[], comments);
 
-        parser.parse(in_string)
+    for node in &document.epilog {
+        let mut misc = String::new();
+        write_canonical_misc(&mut misc, node, comments);
+        if !misc.is_empty() {
+            res.push('\n');
+            res += &misc;
+        }
     }
+
+    res
 }
 
-struct ElementXml<'a, M> {
-    parser: And<'a>,
-    mapper: M,
+// комментарий (только при comments) или инструкция обработки в канонической
+// форме, объявление xml и DOCTYPE не выводятся
+fn write_canonical_misc(res: &mut String, node: &XmlNode, comments: bool) {
+    match node {
+        XmlNode::Comment(comment) if comments => {
+            *res += &format!("<!--{}-->", normalize_newlines(comment))
+        }
+        XmlNode::ProcessingInstruction { target, .. } if target.eq_ignore_ascii_case("xml") => {}
+        // <?target data?>: между целью и данными один пробел, пустые данные опускаются
+        XmlNode::ProcessingInstruction { target, data } => {
+            match normalize_newlines(data.trim_start()) {
+                data if data.is_empty() => *res += &format!("<?{}?>", target),
+                data => *res += &format!("<?{} {}?>", target, data),
+            }
+        }
+        _ => {}
+    }
 }
 
-impl<'a, M> ElementXml<'a, M> {
-    fn new(mapper: M) -> Self {
-        let mut parser = And::new();
-        parser.add_parser(CharSequence::new("<?".to_string()));
-        parser.add_parser(ElementName::new());
+// каноническая форма по Canonical XML 1.0 без комментариев: результат не
+// зависит от записи документа (кавычки, порядок атрибутов, пустые элементы,
+// ссылки на символы, переводы строк), поэтому годится для сравнения и подписи
+pub fn canonicalize(body: &str) -> Result<String, FormatError> {
+    canonicalize_with(body, false, FormatOptions::new())
+}
+
+// то же с сохранением комментариев
+pub fn canonicalize_with_comments(body: &str) -> Result<String, FormatError> {
+    canonicalize_with(body, true, FormatOptions::new())
+}
+
+// comments - сохранять ли комментарии, из options учитывается только
+// ограничение вложенности max_depth
+pub fn canonicalize_with(
+    body: &str,
+    comments: bool,
+    options: impl Into<FormatOptions>,
+) -> Result<String, FormatError> {
+    parse_document(&Document::new(options.into().max_depth), body)
+        .map(|document| print_canonical(&document, comments))
+}
+
+// документ: пробелы вокруг корневого элемента и узлов пролога и эпилога пропускаются
+struct Document {
+    // объявление xml в начале, инструкции обработки, комментарии и DOCTYPE до
+    // корневого элемента необязательны, после него допустимы комментарии и инструкции
+    declaration: ElementXml,
+    prolog_item: Or<Nodes>,
+    epilog_item: Or<Nodes>,
+    root: Element,
+}
 
-        let zero_or_more = ZeroOrMore::new(AnyChar::new(|ch| {
-            return ch != '?' && ch != '/' && ch != '>';
-        }));
-        parser.add_parser(zero_or_more);
+impl Document {
+    fn new(max_depth: usize) -> Self {
+        let mut prolog_item: Or<Nodes> = Or::new();
+        prolog_item.add_parser(ElementXml::new(false));
+        prolog_item.add_parser(Comment::new());
+        prolog_item.add_parser(DocType::new());
 
-        parser.add_parser(CharSequence::new("?>".to_string()));
+        let mut epilog_item: Or<Nodes> = Or::new();
+        epilog_item.add_parser(ElementXml::new(false));
+        epilog_item.add_parser(Comment::new());
 
-        Self { parser, mapper }
+        Self {
+            declaration: ElementXml::new(true),
+            prolog_item,
+            epilog_item,
+            root: Element::new(max_depth),
+        }
     }
 }
 
-impl<'a, M> Parser for ElementXml<'a, M>
-where
-    M: for<'c> Fn(&'c str, usize) -> String + 'a,
-{
-    fn parse<'b>(&self, in_string: &'b str) -> (Result<String, ()>, &'b str) {
-        let res = self.parser.parse(in_string);
-        return if let Ok(r) = res.0 {
-            let r1 = (self.mapper)(&r, 0);
-            (Ok(r1), res.1)
-        } else {
-            res
+impl<'a> Parser<'a, XmlDocument<'a>> for Document {
+    fn parse(&self, in_string: &'a str) -> ParseResult<'a, XmlDocument<'a>> {
+        let mut rest = in_string.trim_start();
+        let mut prolog = Vec::new();
+        if let ParseResult(Ok(node), r, _) = self.declaration.parse(rest) {
+            prolog.push(node);
+            rest = r.trim_start();
+        }
+        let (items, rest, stop) = nodes(&self.prolog_item, rest);
+        prolog.extend(items);
+        let (root, rest, furthest) = match self.root.parse(rest) {
+            ParseResult(Ok(root), rest, f) => (root, rest, furthest_of(f, Some(stop))),
+            ParseResult(Err(e), _, _) => return ParseResult::err(e.furthest(Some(stop)), in_string),
+        };
+        let (epilog, rest, stop) = nodes(&self.epilog_item, rest.trim_start());
+
+        let document = XmlDocument {
+            prolog,
+            root,
+            epilog,
         };
+        ParseResult(Ok(document), rest, furthest_of(Some(stop), furthest))
     }
 }
 
-struct Attribute<'a> {
-    parser: And<'a>,
+// узлы, разделённые пробелами, до первой неудачи
+fn nodes<'a>(
+    item: &impl Parser<'a, XmlNode<'a>>,
+    in_string: &'a str,
+) -> (Vec<XmlNode<'a>>, &'a str, ParseError<'a>) {
+    let mut res = Vec::new();
+    let mut rest = in_string;
+    let mut furthest = None;
+
+    let stop = loop {
+        match item.parse(rest) {
+            ParseResult(Ok(node), r, f) => {
+                res.push(node);
+                rest = r.trim_start();
+                furthest = furthest_of(f, furthest);
+            }
+            ParseResult(Err(e), _, _) => break e.furthest(furthest),
+        }
+    };
+
+    (res, rest, stop)
+}
+
+// разбор документа в дерево
+pub fn parse(body: &str) -> Result<XmlDocument<'_>, FormatError> {
+    parse_with(body, FormatOptions::new())
 }
 
-impl<'a> Attribute<'a> {
-    fn new() -> Self {
-        let mut parser = And::new();
+// то же с ограничением вложенности options.max_depth, остальные настройки
+// на разбор не влияют
+pub fn parse_with(
+    body: &str,
+    options: impl Into<FormatOptions>,
+) -> Result<XmlDocument<'_>, FormatError> {
+    parse_document(&Document::new(options.into().max_depth), body)
+}
 
-        parser.add_parser(ElementName::new());
-        parser.add_parser(CharSequence::new("=\"".to_string()));
+// вывод дерева с отступами и переводами строк из options; узлы пролога и
+// эпилога - каждый на своей строке
+pub fn print(document: &XmlDocument, options: impl Into<FormatOptions>) -> String {
+    let options = options.into();
+    let mut res = String::new();
+
+    for node in &document.prolog {
+        write_inline(&mut res, node, false, &options);
+        res += options.newline();
+    }
+    write_element(&mut res, &document.root, 0, true, &options);
+    for node in &document.epilog {
+        write_inline(&mut res, node, false, &options);
+        res += options.newline();
+    }
 
-        let zero_or_more = ZeroOrMore::new(AnyChar::new(|ch| ch != '"'));
-        parser.add_parser(zero_or_more);
+    options.finish(res)
+}
 
-        parser.add_parser(CharSequence::new("\"".to_string()));
+// элемент с отдельной строки в конец res. Элемент без дочерних элементов
+// выводится одной строкой, кроме корневого (block); смешанное содержимое при
+// WhitespacePolicy::Preserve тоже остаётся одной строкой
+fn write_element(
+    res: &mut String,
+    element: &XmlElement,
+    level: usize,
+    block: bool,
+    options: &FormatOptions,
+) {
+    if element.self_closing {
+        options.line(res, &start_tag(element, "/>", level, options), level);
+        return;
+    }
 
-        Self { parser }
+    let children = &element.children;
+    if !block && !children.iter().any(|child| matches!(child, XmlNode::Element(_))) {
+        options.indent_line(res, level);
+        *res += &start_tag(element, ">", level, options);
+        for child in children {
+            write_inline(res, child, false, options);
+        }
+        write_end_tag(res, element);
+        *res += options.newline();
+        return;
     }
+
+    // при minify смешанное содержимое всегда одной строкой, чтобы пробелы
+    // между текстом и элементами не пропали
+    let preserve = options.xml.whitespace == WhitespacePolicy::Preserve || options.minify;
+    if has_text(children) && preserve {
+        options.indent_line(res, level);
+        write_inline_element(res, element, options);
+        *res += options.newline();
+        return;
+    }
+
+    options.line(res, &start_tag(element, ">", level, options), level);
+    for child in children {
+        write_child(res, child, level + 1, options);
+    }
+
+    options.indent_line(res, level);
+    write_end_tag(res, element);
+    *res += options.newline();
 }
 
-impl<'a> Parser for Attribute<'a> {
-    fn parse<'b>(&self, in_string: &'b str) -> (Result<String, ()>, &'b str) {
-        let res = self.parser.parse(in_string);
-        return if let Ok(r) = res.0 {
-            (Ok(" ".to_string() + &r), res.1)
-        } else {
-            res
-        };
+// ребёнок элемента, выводимого блоком: пробельный текст пропускается,
+// остальное - с отдельной строки
+fn write_child(res: &mut String, child: &XmlNode, level: usize, options: &FormatOptions) {
+    match child {
+        XmlNode::Element(child) => write_element(res, child, level, false, options),
+        XmlNode::Text(text) if text.trim().is_empty() => {}
+        XmlNode::Text(text) => {
+            options.indent_line(res, level);
+            options.push_lines(res, &collapse_edges(text));
+            *res += options.newline();
+        }
+        node => {
+            options.indent_line(res, level);
+            write_inline(res, node, false, options);
+            *res += options.newline();
+        }
     }
 }
 
-struct AttributeList<'a> {
-    parser: ZeroOrMore<And<'a>>,
+fn write_end_tag(res: &mut String, element: &XmlElement) {
+    *res += "</";
+    *res += &element.name;
+    *res += ">";
 }
 
-impl<'a> AttributeList<'a> {
-    fn new() -> Self {
-        let is_space = |ch: char| ch.is_whitespace();
+// узел в одну строку в конец res, текст и вложенные элементы как во входе,
+// только переводы строк внутри узлов заменяются на line_ending. mixed - узел
+// из смешанного содержимого: при minify серии пробелов в нём сжимаются до
+// одного пробела, а вне смешанного содержимого пробельный текст между тегами
+// незначащий и отбрасывается
+fn write_inline(res: &mut String, node: &XmlNode, mixed: bool, options: &FormatOptions) {
+    match node {
+        XmlNode::Element(element) => write_inline_element(res, element, options),
+        XmlNode::Text(text) if options.minify && mixed => collapse_whitespace(res, text),
+        XmlNode::Text(text) if options.minify && text.trim().is_empty() => {}
+        XmlNode::Text(text) => options.push_lines(res, text),
+        XmlNode::Comment(comment) => {
+            *res += "<!--";
+            options.push_lines(res, comment);
+            *res += "-->";
+        }
+        XmlNode::CData(content) => {
+            *res += "<![CDATA[";
+            options.push_lines(res, content);
+            *res += "]]>";
+        }
+        XmlNode::ProcessingInstruction { target, data } => {
+            *res += "<?";
+            *res += target;
+            options.push_lines(res, data);
+            *res += "?>";
+        }
+        XmlNode::DocType(doctype) => options.push_lines(res, doctype),
+    }
+}
 
-        let mut parser = And::new();
-        parser.add_parser(Ignore::new(ZeroOrMore::new(AnyChar::new(is_space.clone()))));
-        parser.add_parser(Attribute::new());
-        parser.add_parser(Ignore::new(ZeroOrMore::new(AnyChar::new(is_space.clone()))));
+fn write_inline_element(res: &mut String, element: &XmlElement, options: &FormatOptions) {
+    write_inline_start(res, element, options);
+    if element.self_closing {
+        return;
+    }
 
-        Self {
-            parser: ZeroOrMore::new(parser),
+    let mixed = mixed_content(&element.children);
+    for child in &element.children {
+        write_inline(res, child, mixed, options);
+    }
+    write_end_tag(res, element);
+}
+
+// среди детей есть текст, кроме пробелов между тегами
+fn has_text(children: &[XmlNode]) -> bool {
+    children
+        .iter()
+        .any(|child| matches!(child, XmlNode::Text(text) if !text.trim().is_empty()))
+}
+
+// смешанное содержимое: текст вперемешку с элементами
+fn mixed_content(children: &[XmlNode]) -> bool {
+    has_text(children) && children.iter().any(|child| matches!(child, XmlNode::Element(_)))
+}
+
+// текст, у которого пробельные символы по краям заменены одним пробелом
+fn collapse_edges(text: &str) -> String {
+    let space = |ch: char| ch.is_ascii_whitespace();
+    let before = if text.starts_with(space) { " " } else { "" };
+    let after = if text.ends_with(space) { " " } else { "" };
+    format!("{}{}{}", before, text.trim_matches(space), after)
+}
+
+// текст в конец res, каждая серия пробельных символов заменена одним пробелом
+fn collapse_whitespace(res: &mut String, text: &str) {
+    let mut space = false;
+    for ch in text.chars() {
+        if ch.is_ascii_whitespace() {
+            space = true;
+            continue;
         }
+        if space {
+            res.push(' ');
+            space = false;
+        }
+        res.push(ch);
+    }
+    if space {
+        res.push(' ');
     }
 }
 
-impl<'a> Parser for AttributeList<'a> {
-    fn parse<'b>(&self, in_string: &'b str) -> (Result<String, ()>, &'b str) {
-        self.parser.parse(in_string)
+// открывающий тег или пустой элемент в одну строку
+fn write_inline_start(res: &mut String, element: &XmlElement, options: &FormatOptions) {
+    *res += "<";
+    *res += &element.name;
+    for attribute in &element.attributes {
+        *res += &print_attribute(attribute, options.xml.quotes);
     }
+    *res += if element.self_closing { "/>" } else { ">" };
 }
 
-pub fn format(body: &str, ident: usize) -> Result<String, String> {
-    let mapper = move |parsed: &str, level: usize| " ".repeat(ident * level) + parsed + "\n";
-    let mut parser = And::new();
+fn start_tag(element: &XmlElement, end: &str, level: usize, options: &FormatOptions) -> String {
+    let attributes: Vec<String> = element
+        .attributes
+        .iter()
+        .map(|attribute| print_attribute(attribute, options.xml.quotes))
+        .collect();
+    wrap_attributes(&element.name, &attributes, end, level, options)
+}
 
-    let is_space = |ch: char| ch.is_whitespace();
-    parser.add_parser(ElementXml::new(mapper));
-    parser.add_parser(Ignore::new(ZeroOrMore::new(AnyChar::new(is_space))));
-    parser.add_parser(ElementAny::new(0, mapper));
-    parser.add_parser(Ignore::new(ZeroOrMore::new(AnyChar::new(is_space))));
+// атрибут в виде " name=\"value\"", кавычки нового стиля внутри значения
+// заменяются на сущности
+fn print_attribute(attribute: &XmlAttribute, quotes: QuoteStyle) -> String {
+    let (quote, value) = match (quotes, attribute.quote) {
+        (QuoteStyle::Double, '\'') => ('"', attribute.value.replace('"', "&quot;")),
+        (QuoteStyle::Single, '"') => ('\'', attribute.value.replace('\'', "&apos;")),
+        (_, quote) => (quote, attribute.value.to_string()),
+    };
 
-    let res = parser.parse(body);
-    return if let Ok(parsed) = res.0 {
-        Ok(parsed)
-    } else {
-        Ok(body.to_string())
+    format!(" {}={}{}{}", attribute.name, quote, value, quote)
+}
+
+pub fn format(body: &str, options: impl Into<FormatOptions>) -> Result<String, FormatError> {
+    let options = options.into().for_input(body);
+    let document = Document::new(options.max_depth);
+    parse_document(&document, body).map(|document| print(&document, options))
+}
+
+// при ошибке разбора возвращает вход без изменений
+pub fn format_lenient(body: &str, options: impl Into<FormatOptions>) -> String {
+    format_lenient_as(body, Format::Xml, options)
+}
+
+// значение псевдоатрибута encoding в объявлении <?xml ...?> в начале body
+pub(crate) fn declared_encoding(body: &str) -> Option<&str> {
+    let data = match ElementXml::new(true).parse(body) {
+        ParseResult(Ok(XmlNode::ProcessingInstruction { target, data }), _, _)
+            if target == "xml" =>
+        {
+            data
+        }
+        _ => return None,
+    };
+    let Cow::Borrowed(data) = data else {
+        return None;
+    };
+
+    let ParseResult(Ok(attributes), _, _) = AttributeList::new().attributes(data) else {
+        return None;
     };
+    attributes.into_iter().find(|a| a.name == "encoding").and_then(|a| match a.value {
+        Cow::Borrowed(value) => Some(value),
+        Cow::Owned(_) => None,
+    })
+}
+
+impl XmlElement<'_> {
+    fn into_owned(self) -> XmlElement<'static> {
+        XmlElement {
+            name: Cow::Owned(self.name.into_owned()),
+            attributes: self.attributes.into_iter().map(XmlAttribute::into_owned).collect(),
+            children: self.children.into_iter().map(XmlNode::into_owned).collect(),
+            self_closing: self.self_closing,
+        }
+    }
+}
+
+impl XmlAttribute<'_> {
+    fn into_owned(self) -> XmlAttribute<'static> {
+        XmlAttribute {
+            name: Cow::Owned(self.name.into_owned()),
+            value: Cow::Owned(self.value.into_owned()),
+            quote: self.quote,
+        }
+    }
+}
+
+impl XmlNode<'_> {
+    fn into_owned(self) -> XmlNode<'static> {
+        let owned = |s: Cow<str>| Cow::Owned(s.into_owned());
+        match self {
+            XmlNode::Element(element) => XmlNode::Element(element.into_owned()),
+            XmlNode::Text(text) => XmlNode::Text(owned(text)),
+            XmlNode::Comment(comment) => XmlNode::Comment(owned(comment)),
+            XmlNode::CData(content) => XmlNode::CData(owned(content)),
+            XmlNode::ProcessingInstruction { target, data } => XmlNode::ProcessingInstruction {
+                target: owned(target),
+                data: owned(data),
+            },
+            XmlNode::DocType(doctype) => XmlNode::DocType(owned(doctype)),
+        }
+    }
+}
+
+// разбор xml по частям: теги и узлы разбираются теми же парсерами, что и в
+// parse, открытые элементы хранятся в стеке. Как выводить элемент, одной
+// строкой или блоком, ясно только по его детям, поэтому элемент копится
+// целиком и выводится через write_element. Если накопленное длиннее CHUNK,
+// самый внешний из копящихся элементов выводится по уже разобранным детям:
+// блоком, если среди них есть элементы, иначе одной строкой
+pub struct XmlStream {
+    // грамматика, как в JsonStream
+    document: Option<Document>,
+    phase: XmlPhase,
+    // открытые элементы, начиная с корневого
+    stack: Vec<Open>,
+    // длина во входе всех копящихся элементов
+    pending: usize,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum XmlPhase {
+    // начало документа, где допустимо объявление xml
+    Declaration,
+    Prolog,
+    Element,
+    Epilog,
+}
+
+// открытый элемент
+enum Open {
+    // вывод ещё не решён: дети копятся в элементе, len - его длина во входе
+    Pending(XmlElement<'static>, usize),
+    // открывающий тег выведен отдельной строкой, дети выводятся по мере разбора
+    Block(XmlElement<'static>),
+    // элемент выводится одной строкой вместе со всем содержимым; флаг -
+    // смешанное ли содержимое по уже разобранным детям, как mixed у write_inline
+    Inline(XmlElement<'static>, bool),
+}
+
+impl Open {
+    fn element(&self) -> &XmlElement<'static> {
+        match self {
+            Open::Pending(element, _) | Open::Block(element) | Open::Inline(element, _) => element,
+        }
+    }
+}
+
+impl XmlStream {
+    // открывающий тег или пустой элемент длиной len во входе
+    fn start(
+        &mut self,
+        element: XmlElement,
+        len: usize,
+        options: &FormatOptions,
+        out: &mut String,
+    ) {
+        let level = self.stack.len();
+        let element = element.into_owned();
+        match self.stack.last_mut() {
+            Some(Open::Inline(..)) => {
+                write_inline_start(out, &element, options);
+                if !element.self_closing {
+                    self.stack.push(Open::Inline(element, false));
+                }
+            }
+            Some(Open::Pending(parent, parent_len)) if element.self_closing => {
+                parent.children.push(XmlNode::Element(element));
+                *parent_len += len;
+                self.pending += len;
+            }
+            _ if element.self_closing => write_element(out, &element, level, level == 0, options),
+            _ => {
+                self.stack.push(Open::Pending(element, len));
+                self.pending += len;
+            }
+        }
+        self.commit(options, out);
+    }
+
+    // текст, комментарий, CDATA или инструкция обработки внутри элемента
+    fn node(&mut self, node: XmlNode, len: usize, options: &FormatOptions, out: &mut String) {
+        let level = self.stack.len();
+        match self.stack.last_mut() {
+            Some(Open::Inline(_, mixed)) => write_inline(out, &node, *mixed, options),
+            Some(Open::Pending(parent, parent_len)) => {
+                parent.children.push(node.into_owned());
+                *parent_len += len;
+                self.pending += len;
+            }
+            _ => write_child(out, &node, level, options),
+        }
+        self.commit(options, out);
+    }
+
+    // закрывающий тег длиной len во входе
+    fn end(&mut self, len: usize, options: &FormatOptions, out: &mut String) {
+        let level = self.stack.len() - 1;
+        match self.stack.pop() {
+            Some(Open::Inline(element, _)) => {
+                write_end_tag(out, &element);
+                if !matches!(self.stack.last(), Some(Open::Inline(..))) {
+                    *out += options.newline();
+                }
+            }
+            Some(Open::Block(element)) => {
+                options.indent_line(out, level);
+                write_end_tag(out, &element);
+                *out += options.newline();
+            }
+            Some(Open::Pending(element, element_len)) => {
+                self.pending += len;
+                match self.stack.last_mut() {
+                    Some(Open::Pending(parent, parent_len)) => {
+                        parent.children.push(XmlNode::Element(element));
+                        *parent_len += element_len + len;
+                    }
+                    _ => {
+                        write_element(out, &element, level, level == 0, options);
+                        self.pending -= element_len + len;
+                    }
+                }
+            }
+            None => {}
+        }
+        self.commit(options, out);
+    }
+
+    // вывод самых внешних копящихся элементов, пока накопленное длиннее CHUNK
+    fn commit(&mut self, options: &FormatOptions, out: &mut String) {
+        while self.pending > CHUNK {
+            let Some(level) = self.stack.iter().position(|open| matches!(open, Open::Pending(..)))
+            else {
+                return;
+            };
+            // внутри копящегося элемента все открытые элементы тоже копятся
+            let mut open: Vec<(XmlElement, usize)> = self
+                .stack
+                .drain(level..)
+                .filter_map(|open| match open {
+                    Open::Pending(element, len) => Some((element, len)),
+                    _ => None,
+                })
+                .collect();
+
+            let children = std::mem::take(&mut open[0].0.children);
+            let nested = open.len() > 1
+                || children.iter().any(|child| matches!(child, XmlNode::Element(_)));
+            let text = has_text(&children);
+            let preserve =
+                options.xml.whitespace == WhitespacePolicy::Preserve || options.minify;
+
+            // решение как в write_element
+            if (nested || level == 0) && !(text && preserve) {
+                let (element, len) = open.remove(0);
+                options.line(out, &start_tag(&element, ">", level, options), level);
+                for child in &children {
+                    write_child(out, child, level + 1, options);
+                }
+                self.stack.push(Open::Block(element));
+                self.stack.extend(open.into_iter().map(|(e, len)| Open::Pending(e, len)));
+                self.pending -= len;
+                continue;
+            }
+
+            options.indent_line(out, level);
+            match nested || level == 0 {
+                true => write_inline_start(out, &open[0].0, options),
+                false => *out += &start_tag(&open[0].0, ">", level, options),
+            }
+            let mut mixed = nested && text;
+            // открытые внутри элементы выводятся в ту же строку
+            let last = open.len() - 1;
+            for (i, (mut element, _)) in open.into_iter().enumerate() {
+                if i > 0 {
+                    write_inline_start(out, &element, options);
+                    mixed = match i < last {
+                        true => has_text(&element.children),
+                        false => mixed_content(&element.children),
+                    };
+                }
+                let children = match i {
+                    0 => &children,
+                    _ => &element.children,
+                };
+                for child in children {
+                    write_inline(out, child, mixed, options);
+                }
+                element.children.clear();
+                self.stack.push(Open::Inline(element, mixed));
+            }
+            self.pending = 0;
+        }
+    }
+
+    // открывающий тег start, разобранный из rest до остатка r; возвращает его длину
+    fn element_start<'a>(
+        &mut self,
+        start: XmlElement<'a>,
+        rest: &'a str,
+        r: &'a str,
+        options: &FormatOptions,
+        out: &mut String,
+    ) -> Result<usize, ParseError<'a>> {
+        if self.stack.len() >= options.max_depth {
+            return Err(ParseError::too_deep(rest, options.max_depth));
+        }
+        // префиксы, объявленные открытыми элементами, как scope у Element
+        let mut scope = Vec::new();
+        for open in &self.stack {
+            declare(open.element(), &mut scope);
+        }
+        if let Some(name) = undeclared_prefix(&start, &mut scope) {
+            return Err(ParseError::new(&rest[offset_in(rest, name)..], PREFIX));
+        }
+
+        let len = rest.len() - r.len();
+        self.start(start, len, options, out);
+        self.phase = match self.stack.is_empty() {
+            true => XmlPhase::Epilog,
+            false => XmlPhase::Element,
+        };
+        Ok(len)
+    }
+
+    // имя самого вложенного открытого элемента
+    fn open_name(&self) -> &str {
+        self.stack.last().map_or("", |open| &open.element().name)
+    }
+
+    // закрывающий тег, разобранный из rest до остатка r; возвращает его длину
+    fn element_end(
+        &mut self,
+        rest: &str,
+        r: &str,
+        options: &FormatOptions,
+        out: &mut String,
+    ) -> usize {
+        let len = rest.len() - r.len();
+        self.end(len, options, out);
+        if self.stack.is_empty() {
+            self.phase = XmlPhase::Epilog;
+        }
+        len
+    }
+
+    // шаг разбора грамматикой document
+    fn run(
+        &mut self,
+        document: &Document,
+        input: &str,
+        end: bool,
+        options: &FormatOptions,
+        out: &mut String,
+    ) -> Result<usize, FormatError> {
+        let element = &document.root;
+
+        let mut rest = input;
+        loop {
+            // пробелы вне корневого элемента не выводятся
+            if self.phase != XmlPhase::Element {
+                rest = rest.trim_start();
+            }
+            let done = self.phase == XmlPhase::Epilog && rest.is_empty();
+            if done || (!end && rest.len() < LOOKAHEAD) {
+                break;
+            }
+
+            let step = match self.phase {
+                XmlPhase::Declaration => match document.declaration.parse(rest) {
+                    ParseResult(Ok(_), r, _) if r.len() < LOOKAHEAD && !end => break,
+                    ParseResult(Err(e), _, _) if e.rest.len() < LOOKAHEAD && !end => break,
+                    res => {
+                        self.phase = XmlPhase::Prolog;
+                        match res {
+                            ParseResult(Ok(node), r, _) => {
+                                write_inline(out, &node, false, options);
+                                *out += options.newline();
+                                Ok(rest.len() - r.len())
+                            }
+                            // объявления нет - дальше пролог с того же места
+                            ParseResult(Err(_), _, _) => Ok(0),
+                        }
+                    }
+                },
+                XmlPhase::Prolog => match document.prolog_item.parse(rest) {
+                    ParseResult(Ok(_), r, _) if r.len() < LOOKAHEAD && !end => break,
+                    ParseResult(Ok(node), r, _) => {
+                        write_inline(out, &node, false, options);
+                        *out += options.newline();
+                        Ok(rest.len() - r.len())
+                    }
+                    ParseResult(Err(stop), _, _) => match element.start.parse(rest) {
+                        ParseResult(Ok(_), r, _) if r.len() < LOOKAHEAD && !end => break,
+                        ParseResult(Ok(start), r, _) => {
+                            self.element_start(start, rest, r, options, out)
+                        }
+                        ParseResult(Err(e), _, _) => Err(e.furthest(Some(stop))),
+                    },
+                },
+                XmlPhase::Element => match element.start.parse(rest) {
+                    ParseResult(Ok(_), r, _) if r.len() < LOOKAHEAD && !end => break,
+                    ParseResult(Ok(start), r, _) => {
+                        self.element_start(start, rest, r, options, out)
+                    }
+                    // дальше как в Element::node и закрывающий тег
+                    ParseResult(Err(e), _, _) => match element.child.parse(rest) {
+                        ParseResult(Ok(_), r, _) if r.len() < LOOKAHEAD && !end => break,
+                        ParseResult(Ok(node), r, _) => {
+                            self.node(node, rest.len() - r.len(), options, out);
+                            Ok(rest.len() - r.len())
+                        }
+                        ParseResult(Err(child), _, _) => match element.close.parse(rest) {
+                            ParseResult(Ok(close), _, _)
+                                if ElementClose::name(close) != self.open_name() =>
+                            {
+                                let expected = format!("`</{}>`", self.open_name());
+                                Err(ParseError::new(rest, expected))
+                            }
+                            ParseResult(Ok(_), r, _) => Ok(self.element_end(rest, r, options, out)),
+                            ParseResult(Err(close), _, _) => {
+                                Err(close.furthest(Some(child.furthest(Some(e)))))
+                            }
+                        },
+                    },
+                },
+                XmlPhase::Epilog => match document.epilog_item.parse(rest) {
+                    ParseResult(Ok(_), r, _) if r.len() < LOOKAHEAD && !end => break,
+                    ParseResult(Ok(node), r, _) => {
+                        write_inline(out, &node, false, options);
+                        *out += options.newline();
+                        Ok(rest.len() - r.len())
+                    }
+                    // узел после корневого элемента не начался - лишние символы
+                    ParseResult(Err(e), _, _) if e.rest.len() == rest.len() => {
+                        return Err(FormatError::trailing(input, rest));
+                    }
+                    ParseResult(Err(e), _, _) => Err(e),
+                },
+            };
+
+            match step {
+                Ok(len) => rest = &rest[len..],
+                // ошибка у конца входа - лексема ещё не пришла целиком
+                Err(e) if e.rest.len() < LOOKAHEAD && e.too_deep.is_none() && !end => break,
+                Err(e) => return Err(FormatError::syntax(input, &e)),
+            }
+        }
+
+        Ok(input.len() - rest.len())
+    }
+}
+
+impl Machine for XmlStream {
+    fn new(options: &FormatOptions) -> Self {
+        Self {
+            document: Some(Document::new(options.max_depth)),
+            phase: XmlPhase::Declaration,
+            stack: Vec::new(),
+            pending: 0,
+        }
+    }
+
+    fn advance(
+        &mut self,
+        input: &str,
+        end: bool,
+        options: &FormatOptions,
+        out: &mut String,
+    ) -> Result<usize, FormatError> {
+        // грамматика на время шага вынимается, чтобы разбирать ею, пока
+        // меняется состояние разбора
+        let document = self.document.take().expect("grammar is put back after each step");
+        let res = self.run(&document, input, end, options, out);
+        self.document = Some(document);
+        res
+    }
+}
+
+// форматирование из reader в writer по частям, без чтения всего входа в память
+pub fn format_stream<R: Read, W: Write>(
+    reader: R,
+    writer: W,
+    options: impl Into<FormatOptions>,
+) -> Result<(), FormatError> {
+    stream::copy(Stream::<XmlStream>::new(options.into()), reader, writer)
 }
 
+// форматирование xml, который приходит частями
+pub type XmlFormatter = Formatter<XmlStream>;
+
 #[cfg(test)]
 mod tests {
     use super::{
-        Attribute, AttributeList, ElementAny, ElementClose, ElementFull, ElementName, ElementOpen,
-        ElementWithText, ElementXml, Parser, format
+        canonicalize, canonicalize_with, canonicalize_with_comments, format, format_lenient,
+        parse, parse_with, print, write_element, Attribute, AttributeList, DocType, Element,
+        ElementClose, ElementName, ElementXml, Parser, QuoteStyle, StartTag, WhitespacePolicy,
+        XmlAttribute, XmlElement, XmlNode, XmlOptions,
     };
+    use crate::{ErrorKind, FormatOptions, ParseResult, DEFAULT_MAX_DEPTH};
+
+    fn attribute(name: &'static str, value: &'static str) -> XmlAttribute<'static> {
+        XmlAttribute {
+            name: name.into(),
+            value: value.into(),
+            quote: '"',
+        }
+    }
+
+    // элемент, выведенный с отступом в 4 пробела, и остаток входа
+    fn element(input: &str) -> (String, &str) {
+        let res: ParseResult<XmlElement> = Element::new(DEFAULT_MAX_DEPTH).parse(input);
+        let mut printed = String::new();
+        write_element(&mut printed, &res.0.unwrap(), 0, false, &FormatOptions::default());
+        (printed, res.1)
+    }
 
     #[test]
     fn parse_element_name() {
@@ -388,7 +1730,7 @@ mod tests {
         assert_eq!("", res.1);
 
         let res = parser.parse("1hello");
-        assert_eq!(Err(()), res.0);
+        assert_eq!("name", res.0.unwrap_err().describe().unwrap());
         assert_eq!("1hello", res.1);
 
         let res = parser.parse("hello_world");
@@ -402,6 +1744,15 @@ mod tests {
         let res = parser.parse("hello attr=");
         assert_eq!("hello", res.0.unwrap());
         assert_eq!(" attr=", res.1);
+
+        // имя по продукции Name: `_` и `:` в начале, `.` и цифры дальше
+        for name in ["_a", ":a", "_1.b-c", "имя", "a\u{B7}b"] {
+            assert_eq!(name, parser.parse(name).0.unwrap());
+        }
+        for name in ["-a", ".a", "1a", "\u{B7}a"] {
+            assert!(parser.parse(name).0.is_err(), "{}", name);
+        }
+        assert_eq!("<_a/>\n", format("<_a/>", 4).unwrap());
     }
 
     #[test]
@@ -409,136 +1760,128 @@ mod tests {
         let parser = Attribute::new();
 
         let res = parser.parse("hello=\"1\"");
-        assert_eq!(" hello=\"1\"", res.0.unwrap());
+        assert_eq!(attribute("hello", "1"), res.0.unwrap());
         assert_eq!("", res.1);
     }
 
     #[test]
     fn parse_attribute_list() {
         let parser = AttributeList::new();
-
-        let res = parser.parse(" hello=\"1\" test=\"aaabbb\"");
-        assert_eq!(" hello=\"1\" test=\"aaabbb\"", res.0.unwrap());
-        assert_eq!("", res.1);
-
-        let res = parser.parse("\n hello=\"1\"\n\n\n test=\"aaabbb\"");
-        assert_eq!(" hello=\"1\" test=\"aaabbb\"", res.0.unwrap());
-        assert_eq!("", res.1);
-
-        let res = parser.parse("\n hello=\"1\"\n\n\n test=\"aaabbb\" \t\t\n");
-        assert_eq!(" hello=\"1\" test=\"aaabbb\"", res.0.unwrap());
-        assert_eq!("", res.1);
+        let expected = vec![attribute("hello", "1"), attribute("test", "aaabbb")];
+
+        let res = parser.attributes(" hello=\"1\" test=\"aaabbb\"");
+        assert_eq!((expected.clone(), ""), (res.0.unwrap(), res.1));
+
+        let res = parser.attributes("\n hello=\"1\"\n\n\n test=\"aaabbb\"");
+        assert_eq!((expected.clone(), ""), (res.0.unwrap(), res.1));
+
+        let res = parser.attributes("\n hello=\"1\"\n\n\n test=\"aaabbb\" \t\t\n");
+        assert_eq!((expected, ""), (res.0.unwrap(), res.1));
+
+        // повтор имени и атрибуты без пробела между ними
+        let err = format("<a x='1' x='2'/>", 4).unwrap_err();
+        assert_eq!((ErrorKind::Syntax, 9), (err.kind(), err.offset()));
+        assert_eq!(Some("attribute other than `x`"), err.expected());
+        let err = format("<a>\n<b x='1'y='2'/></a>", 4).unwrap_err();
+        assert_eq!((ErrorKind::Syntax, 2, 9), (err.kind(), err.line(), err.column()));
+        assert_eq!(Some("whitespace"), err.expected());
+        assert!(canonicalize("<a x='1' x='1'/>").is_err());
+        assert!(format("<a x='1' y='2'/>", 4).is_ok());
     }
 
     #[test]
-    fn parse_full_element() {
-        let parser = ElementFull::new(0, |parsed: &str, _: usize| parsed.to_string());
-        let res = parser.parse("<body>");
-        assert_eq!(true, res.0.is_err());
-        assert_eq!("<body>", res.1);
+    fn parse_start_tag() {
+        let parser = StartTag::new();
 
         let res = parser.parse("<body/>");
-        assert_eq!("<body/>", res.0.unwrap());
+        let body = XmlElement {
+            name: "body".into(),
+            attributes: Vec::new(),
+            children: Vec::new(),
+            self_closing: true,
+        };
+        assert_eq!(body, res.0.unwrap());
         assert_eq!("", res.1);
 
-        let res = parser.parse("<body aaa=\"bbb\"/>");
-        assert_eq!("<body aaa=\"bbb\"/>", res.0.unwrap());
-        assert_eq!("", res.1);
+        let res = parser.parse("<body aaa=\"bbb\">rest");
+        let open = XmlElement {
+            attributes: vec![attribute("aaa", "bbb")],
+            self_closing: false,
+            ..body
+        };
+        assert_eq!(open, res.0.unwrap());
+        assert_eq!("rest", res.1);
 
         let res = parser.parse("<body aaa=\"bbb\"");
-        assert_eq!(true, res.0.is_err());
+        assert!(res.0.is_err());
         assert_eq!("<body aaa=\"bbb\"", res.1);
     }
 
-    #[test]
-    fn parse_element_open() {
-        let parser = ElementOpen::new(0, |parsed: &str, _: usize| parsed.to_string());
-        let res = parser.parse("<body>");
-        assert_eq!("<body>", res.0.unwrap());
-        assert_eq!("", res.1);
-
-        let res = parser.parse("<body aaa=\"bbb\">");
-        assert_eq!("<body aaa=\"bbb\">", res.0.unwrap());
-        assert_eq!("", res.1);
-
-        let res = parser.parse("<body aaa=\"bbb\"/>");
-        assert_eq!(true, res.0.is_err());
-        assert_eq!("<body aaa=\"bbb\"/>", res.1);
-    }
-
     #[test]
     fn parse_element_close() {
-        let parser = ElementClose::new(0, |parsed: &str, _: usize| parsed.to_string());
+        let parser = ElementClose::new();
         let res = parser.parse("</body>");
         assert_eq!("</body>", res.0.unwrap());
         assert_eq!("", res.1);
 
         let res = parser.parse("</body aaa=\"bbb\">");
-        assert_eq!(true, res.0.is_err());
+        assert!(res.0.is_err());
         assert_eq!("</body aaa=\"bbb\">", res.1);
+
+        // ETag ::= '</' Name S? '>'
+        let res = parser.parse("</body \n\t>rest");
+        assert_eq!("body", ElementClose::name(res.0.unwrap()));
+        assert_eq!("rest", res.1);
+        assert!(parser.parse("</ body>").0.is_err());
+        assert_eq!("<a>\n  <b>x</b>\n</a>\n", format("<a><b>x</b ></a\n>", 2).unwrap());
     }
 
     #[test]
     fn parse_element_with_text() {
-        let parser = ElementWithText::new(0, |parsed: &str, level: usize| {
-            " ".repeat(level * 4) + parsed + "\n"
-        });
-        let res = parser.parse("<body></body>");
-        assert_eq!("<body></body>\n", res.0.unwrap());
-        assert_eq!("", res.1);
-
-        let res = parser.parse("<body>aaabbb</body>");
-        assert_eq!("<body>aaabbb</body>\n", res.0.unwrap());
-        assert_eq!("", res.1);
+        assert_eq!(("<body></body>\n".to_string(), ""), element("<body></body>"));
+        assert_eq!(("<body>aaabbb</body>\n".to_string(), ""), element("<body>aaabbb</body>"));
+        assert_eq!(("<body/>\n".to_string(), ""), element("<body/>"));
     }
 
     #[test]
     fn parse_element_with_children() {
-        let parser = ElementAny::new(0, |parsed: &str, level: usize| {
-            " ".repeat(level * 4) + parsed + "\n"
-        });
+        let res = element("<body><inner></inner></body>");
+        assert_eq!(("<body>\n    <inner></inner>\n</body>\n".to_string(), ""), res);
 
-        let res = parser.parse("<body><inner></inner></body>");
-        assert_eq!("<body>\n    <inner></inner>\n</body>\n", res.0.unwrap());
-        assert_eq!("", res.1);
-
-        let res = parser.parse("<body><inner>a</inner><inner>b</inner></body>");
+        let res = element("<body><inner>a</inner><inner>b</inner></body>");
         assert_eq!(
             "<body>\n    <inner>a</inner>\n    <inner>b</inner>\n</body>\n",
-            res.0.unwrap()
+            res.0
         );
         assert_eq!("", res.1);
 
-        let res = parser.parse("<body><inner>a</inner><inner>b</inner>");
-        assert_eq!(true, res.0.is_err());
+        let res: ParseResult<XmlElement> =
+            Element::new(DEFAULT_MAX_DEPTH).parse("<body><inner>a</inner><inner>b</inner>");
+        assert!(res.0.is_err());
         assert_eq!("<body><inner>a</inner><inner>b</inner>", res.1);
-
-        let res = parser.parse("<body><inner>a</inner><inner>b</inner></body>");
-        assert_eq!(
-            "<body>\n    <inner>a</inner>\n    <inner>b</inner>\n</body>\n",
-            res.0.unwrap()
-        );
     }
 
     #[test]
     fn space_ignoring() {
-        let parser = ElementAny::new(0, |parsed: &str, level: usize| {
-            " ".repeat(level * 4) + parsed + "\n"
-        });
         let input = "<body>    <node>test</node>          </body>\n";
         let expect = "<body>\n    <node>test</node>\n</body>\n";
-        let res = parser.parse(input);
-        assert_eq!(expect, res.0.unwrap());
+        assert_eq!((expect.to_string(), "\n"), element(input));
     }
 
     #[test]
     fn xml_prefix_element() {
         let input = "<?xml version=\"1.0\" encoding=\"UTF-8\"?>";
-        let expect = "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n";
+        let expect = XmlNode::ProcessingInstruction {
+            target: "xml".into(),
+            data: " version=\"1.0\" encoding=\"UTF-8\"".into(),
+        };
 
-        let parser = ElementXml::new(|parsed: &str, _: usize| parsed.to_string() + "\n");
-        let res = parser.parse(input);
+        let res = ElementXml::new(true).parse(input);
         assert_eq!(expect, res.0.unwrap());
+
+        let res = ElementXml::new(false).parse(input);
+        assert_eq!(2, input.len() - res.0.unwrap_err().rest.len());
+        assert!(ElementXml::new(false).parse("<?xml-stylesheet a?>").0.is_ok());
     }
 
     #[test]
@@ -562,21 +1905,394 @@ mod tests {
     #[test]
     fn real_soap_xml() {
         let content = include_str!("testdata/request.xml");
-        let res = format(&content, 4);
-        assert_eq!(true, res.is_ok());
+        let res = format(content, 4);
+        assert!(res.is_ok());
 
         let content = include_str!("testdata/response.xml");
-        let res = format(&content, 4);
-        assert_eq!(true, res.is_ok());
+        let res = format(content, 4);
+        assert!(res.is_ok());
     }
 
     #[test]
     fn complex_soap_xml() {
         let content = include_str!("testdata/sirena_request.xml");
-        let res = format(&content, 4);
-        assert_eq!(true, res.is_ok());
+        let res = format(content, 4);
+        assert!(res.is_ok());
 
         let content = include_str!("testdata/sirena_response.xml");
         assert_eq!(res.unwrap(), content);
     }
+
+    #[test]
+    fn format_errors() {
+        let err = format("<?xml version=\"1.0\"?><a></a><b/>", 4).unwrap_err();
+        assert_eq!(ErrorKind::TrailingCharacters, err.kind());
+        assert_eq!(28, err.offset());
+
+        let err = format("<?xml version=\"1.0\"?><a><b></a>", 4).unwrap_err();
+        assert_eq!(ErrorKind::Syntax, err.kind());
+        assert_eq!(Some("`</b>`"), err.expected());
+
+        let err = format("<?xml version=\"1.0\"?>\n<a>\n  <b c=\"1/>\n</a>", 4).unwrap_err();
+        assert_eq!(ErrorKind::UnexpectedEnd, err.kind());
+        assert_eq!(Some("`\"`"), err.expected());
+
+        let err = format("<?xml version=\"1.0\"?>\n<a>\n  <b c=1/>\n</a>", 4).unwrap_err();
+        assert_eq!("expected `\"` or `'` at line 3, column 8", err.to_string());
+
+        let input = "<?xml version=\"1.0\"?><a>";
+        assert_eq!(input, format_lenient(input, 4));
+    }
+
+    #[test]
+    fn comments_cdata_doctype() {
+        let input = "<?xml version=\"1.0\"?><!-- до --><!DOCTYPE a [<!ELEMENT a (#PCDATA)>]><a><!-- внутри --><![CDATA[ <x> & ]]><b>t<!-- c --><![CDATA[]]></b></a><!-- после -->";
+        let expected = "<?xml version=\"1.0\"?>\n<!-- до -->\n<!DOCTYPE a [<!ELEMENT a (#PCDATA)>]>\n<a>\n  <!-- внутри -->\n  <![CDATA[ <x> & ]]>\n  <b>t<!-- c --><![CDATA[]]></b>\n</a>\n<!-- после -->\n";
+        assert_eq!(expected, format(input, 2).unwrap());
+
+        let res = DocType::new().parse("<!DOCTYPE a SYSTEM \"a>b.dtd\">rest");
+        let doctype = XmlNode::DocType("<!DOCTYPE a SYSTEM \"a>b.dtd\">".into());
+        assert_eq!(doctype, res.0.unwrap());
+        assert_eq!("rest", res.1);
+
+        let err = format("<?xml version=\"1.0\"?><a><!-- x </a>", 2).unwrap_err();
+        assert_eq!(ErrorKind::UnexpectedEnd, err.kind());
+        assert_eq!(Some("`-->`"), err.expected());
+
+        // `--` внутри комментария запрещено
+        for input in ["<a><!-- a -- b --></a>", "<a><!-- a ---></a>"] {
+            let err = format(input, 2).unwrap_err();
+            assert_eq!(ErrorKind::Syntax, err.kind(), "{}", input);
+            assert_eq!(Some("`-->`"), err.expected());
+            assert_eq!(10, err.offset());
+        }
+        assert_eq!("<a>\n  <!---->\n</a>\n", format("<a><!----></a>", 2).unwrap());
+    }
+
+    #[test]
+    fn processing_instructions() {
+        assert_eq!(
+            "<a>\n  <b>t</b>\n</a>\n",
+            format("<a><b>t</b></a>", 2).unwrap()
+        );
+
+        let input = "<?xml version=\"1.0\"?><?xml-stylesheet type=\"text/xsl\" href=\"/s/a.xsl\"?><a><?php echo 1; ?><b>t<?pi?></b></a><?end?>";
+        let expected = "<?xml version=\"1.0\"?>\n<?xml-stylesheet type=\"text/xsl\" href=\"/s/a.xsl\"?>\n<a>\n  <?php echo 1; ?>\n  <b>t<?pi?></b>\n</a>\n<?end?>\n";
+        assert_eq!(expected, format(input, 2).unwrap());
+
+        let err = format("<?xml version=\"1.0\"<a/>", 2).unwrap_err();
+        assert_eq!(ErrorKind::UnexpectedEnd, err.kind());
+        assert_eq!(Some("`?>`"), err.expected());
+        // объявление xml - только в начале документа
+        let cases = [
+            ("<a><?xml version=\"1.0\"?></a>", 5),
+            ("<!-- c --><?xml version=\"1.0\"?><a/>", 12),
+            ("<?xml version=\"1.0\"?><?XML version=\"1.0\"?><a/>", 23),
+            ("<a/>\n<?xml version=\"1.0\"?>", 7),
+        ];
+        for (input, offset) in cases {
+            let err = format(input, 2).unwrap_err();
+            assert_eq!(ErrorKind::Syntax, err.kind(), "{}", input);
+            assert_eq!(Some("target other than `xml`"), err.expected());
+            assert_eq!(offset, err.offset(), "{}", input);
+            assert!(canonicalize(input).is_err(), "{}", input);
+        }
+        let expected = "<?xml version=\"1.0\"?>\n<a/>\n";
+        assert_eq!(expected, format("\n<?xml version=\"1.0\"?><a/>", 2).unwrap());
+    }
+
+    #[test]
+    fn mixed_content() {
+        let input = "<div><p>Hello <b>wor<i>ld</i></b>!</p>\n  <p>Hi</p></div>";
+        let expected = "<div>\n  <p>Hello <b>wor<i>ld</i></b>!</p>\n  <p>Hi</p>\n</div>\n";
+        assert_eq!(expected, format(input, 2).unwrap());
+
+        let input = "<p>\n  Hello\n  <b>world</b>   <br/>\n!</p>";
+        let expected = "<p>\n   Hello \n  <b>world</b>\n  <br/>\n   !\n</p>\n";
+        let xml = XmlOptions {
+            whitespace: WhitespacePolicy::Reflow,
+            ..XmlOptions::default()
+        };
+        let options = FormatOptions::new().indent(2).xml(xml);
+        assert_eq!(expected, format(input, options).unwrap());
+
+        // пробелы между словами и элементами не теряются
+        let input = "<p>a <b>b</b> c<i>d</i>, again</p>";
+        let expected = "<p>\n  a \n  <b>b</b>\n   c\n  <i>d</i>\n  , again\n</p>\n";
+        assert_eq!(expected, format(input, options).unwrap());
+
+        let err = format("<p>Hello <b>world</p>", 2).unwrap_err();
+        assert_eq!(ErrorKind::Syntax, err.kind());
+        assert_eq!(17, err.offset());
+    }
+
+    #[test]
+    fn attribute_grammar() {
+        let parser = Attribute::new();
+        let res = parser.parse("a = 'x \"y\" &amp; &#233; &#xE9;'/>");
+        let expected = XmlAttribute {
+            quote: '\'',
+            ..attribute("a", "x \"y\" &amp; &#233; &#xE9;")
+        };
+        assert_eq!(expected, res.0.unwrap());
+        assert_eq!("/>", res.1);
+
+        for input in ["a='1 & 2'", "a='&#0;'", "a='&#xZ;'", "a='&1a;'", "a='1<2'"] {
+            assert!(parser.parse(input).0.is_err(), "{}", input);
+        }
+
+        let err = format("<a b='&amp'/>", 2).unwrap_err();
+        assert_eq!(
+            "expected entity reference at line 1, column 7",
+            err.to_string()
+        );
+
+        let input = "<a b='say \"hi\"' c = \"it's\"><d e='1'/></a>";
+        let options = XmlOptions {
+            quotes: QuoteStyle::Double,
+            ..XmlOptions::default()
+        };
+        let expected = "<a b=\"say &quot;hi&quot;\" c=\"it's\">\n  <d e=\"1\"/>\n</a>\n";
+        assert_eq!(expected, format(input, FormatOptions::new().indent(2).xml(options)).unwrap());
+
+        let options = XmlOptions {
+            quotes: QuoteStyle::Single,
+            ..XmlOptions::default()
+        };
+        let expected = "<a b='say \"hi\"' c='it&apos;s'>\n  <d e='1'/>\n</a>\n";
+        assert_eq!(expected, format(input, FormatOptions::new().indent(2).xml(options)).unwrap());
+    }
+
+    #[test]
+    fn wrap_attributes() {
+        let input = "<a><b x=\"1\" yy=\"2\"><c z='3'/></b></a>";
+
+        let options = FormatOptions::new().indent(2).max_width(14);
+        let expected = "<a>\n  <b\n   x=\"1\"\n   yy=\"2\">\n    <c z='3'/>\n  </b>\n</a>\n";
+        assert_eq!(expected, format(input, options).unwrap());
+
+        let options = XmlOptions {
+            max_attributes: Some(1),
+            ..XmlOptions::default()
+        };
+        assert_eq!(expected, format(input, FormatOptions::new().indent(2).xml(options)).unwrap());
+
+        let res = format(input, 2).unwrap();
+        assert_eq!(
+            "<a>\n  <b x=\"1\" yy=\"2\">\n    <c z='3'/>\n  </b>\n</a>\n",
+            res
+        );
+
+        let options = FormatOptions::new().max_width(80);
+        let res = format(include_str!("testdata/response.xml"), options).unwrap();
+        assert!(res.starts_with("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n<SOAP-ENV:Envelope\n xmlns:SOAP-ENV=\"http://schemas.xmlsoap.org/soap/envelope/\"\n xmlns:awsse="));
+    }
+
+    #[test]
+    fn tree() {
+        let input = "<?xml version=\"1.0\"?><a x='1'>t<b/><!--c--></a>";
+        let mut document = parse(input).unwrap();
+        assert_eq!(1, document.prolog.len());
+        assert_eq!("a", document.root.name);
+        let x = XmlAttribute {
+            quote: '\'',
+            ..attribute("x", "1")
+        };
+        assert_eq!(vec![x], document.root.attributes);
+        assert_eq!(XmlNode::Text("t".into()), document.root.children[0]);
+        assert_eq!(XmlNode::Comment("c".into()), document.root.children[2]);
+
+        // дерево можно изменить и вывести без повторного разбора
+        document.root.attributes[0].value = "***".into();
+        document.root.children.remove(0);
+        let expected = "<?xml version=\"1.0\"?>\n<a x='***'>\n  <b/>\n  <!--c-->\n</a>\n";
+        assert_eq!(expected, print(&document, 2));
+
+        assert_eq!("<a/>\n", format("<a/>", 2).unwrap());
+        assert!(parse("<a>").is_err());
+    }
+
+    // примеры из раздела 3 спецификации Canonical XML 1.0
+    #[test]
+    fn canonical() {
+        let input = r#"<?xml version="1.0"?>
+
+<?xml-stylesheet   href="doc.xsl"
+   type="text/xsl"   ?>
+
+<!DOCTYPE doc SYSTEM "doc.dtd">
+
+<doc>Hello, world!<!-- Comment 1 --></doc>
+
+<?pi-without-data     ?>
+
+<!-- Comment 2 -->
+
+<!-- Comment 3 -->"#;
+        let expected = r#"<?xml-stylesheet href="doc.xsl"
+   type="text/xsl"   ?>
+<doc>Hello, world!</doc>
+<?pi-without-data?>"#;
+        assert_eq!(expected, canonicalize(input).unwrap());
+        let expected = r#"<?xml-stylesheet href="doc.xsl"
+   type="text/xsl"   ?>
+<doc>Hello, world!<!-- Comment 1 --></doc>
+<?pi-without-data?>
+<!-- Comment 2 -->
+<!-- Comment 3 -->"#;
+        assert_eq!(expected, canonicalize_with_comments(input).unwrap());
+
+        let input = "<doc>
+   <clean>   </clean>
+   <dirty>   A   B   </dirty>
+   <mixed>
+      A
+      <clean>   </clean>
+      B
+      <dirty>   A   B   </dirty>
+      C
+   </mixed>
+</doc>";
+        assert_eq!(input, canonicalize(input).unwrap());
+
+        // атрибуты по умолчанию из DTD не добавляются
+        let input = r#"<doc>
+   <e1   />
+   <e2   ></e2   >
+   <e3   name = "elem3"   id="elem3"   />
+   <e4   name="elem4"   id="elem4"   ></e4>
+   <e5 a:attr="out" b:attr="sorted" attr2="all" attr="I'm"
+      xmlns:b="http://www.ietf.org"
+      xmlns:a="http://www.w3.org"
+      xmlns="http://example.org"/>
+   <e6 xmlns="" xmlns:a="http://www.w3.org">
+      <e7 xmlns="http://www.ietf.org">
+         <e8 xmlns="" xmlns:a="http://www.w3.org">
+            <e9 xmlns="" xmlns:a="http://www.ietf.org"/>
+         </e8>
+      </e7>
+   </e6>
+</doc>"#;
+        let expected = r#"<doc>
+   <e1></e1>
+   <e2></e2>
+   <e3 id="elem3" name="elem3"></e3>
+   <e4 id="elem4" name="elem4"></e4>
+   <e5 xmlns="http://example.org" xmlns:a="http://www.w3.org" xmlns:b="http://www.ietf.org" attr="I'm" attr2="all" b:attr="sorted" a:attr="out"></e5>
+   <e6 xmlns:a="http://www.w3.org">
+      <e7 xmlns="http://www.ietf.org">
+         <e8 xmlns="">
+            <e9 xmlns:a="http://www.ietf.org"></e9>
+         </e8>
+      </e7>
+   </e6>
+</doc>"#;
+        assert_eq!(expected, canonicalize(input).unwrap());
+
+        let input = r#"<doc>
+   <text>First line&#x0d;&#10;Second line</text>
+   <value>&#x32;</value>
+   <compute><![CDATA[value>"0" && value<"10" ?"valid":"error"]]></compute>
+   <compute expr='value>"0" &amp;&amp; value&lt;"10" ?"valid":"error"'>valid</compute>
+   <norm attr=' &apos;   &#x20;&#13;&#xa;&#9;   &apos; '/>
+</doc>"#;
+        let expected = r#"<doc>
+   <text>First line&#xD;
+Second line</text>
+   <value>2</value>
+   <compute>value&gt;"0" &amp;&amp; value&lt;"10" ?"valid":"error"</compute>
+   <compute expr="value>&quot;0&quot; &amp;&amp; value&lt;&quot;10&quot; ?&quot;valid&quot;:&quot;error&quot;">valid</compute>
+   <norm attr=" '    &#xD;&#xA;&#x9;   ' "></norm>
+</doc>"#;
+        assert_eq!(expected, canonicalize(input).unwrap());
+
+        let input = "<?xml version=\"1.0\" encoding=\"ISO-8859-1\"?>\r\n<doc>&#169;\r\n<a b='1\r\n2'/></doc>\r\n";
+        let expected = "<doc>©\n<a b=\"1 2\"></a></doc>";
+        assert_eq!(expected, canonicalize(input).unwrap());
+
+        let err = canonicalize("<a><b></a></b>").unwrap_err();
+        assert_eq!(ErrorKind::Syntax, err.kind());
+        assert_eq!(Some("`</b>`"), err.expected());
+        assert_eq!(6, err.offset());
+
+        // разбор общий с format: что принимает один, принимает и другой
+        assert_eq!("<a><b>x</b></a>", canonicalize("<a><b>x</b ></a\n>").unwrap());
+        for input in ["<a>x & y</a>", "<a>x &#0; y</a>"] {
+            let err = canonicalize(input).unwrap_err();
+            assert_eq!(Some("entity reference"), err.expected());
+            assert_eq!(5, err.offset());
+            assert_eq!(err.to_string(), format(input, 2).unwrap_err().to_string());
+        }
+
+        let err = canonicalize("<a>").unwrap_err();
+        assert_eq!(ErrorKind::UnexpectedEnd, err.kind());
+    }
+
+    #[test]
+    fn namespace_prefixes() {
+        let input = "<a xmlns:p='u' xml:lang='ru'><p:b p:x='1'/><c xmlns:q='v'><q:d/></c></a>";
+        assert!(format(input, 2).is_ok());
+        assert!(canonicalize(input).is_ok());
+        assert_eq!("<:a/>\n", format("<:a/>", 2).unwrap());
+
+        for (input, offset) in [
+            ("<p:a/>", 1),
+            ("<a p:x='1'/>", 3),
+            ("<a><b xmlns:p='u'/><p:c/></a>", 20),
+        ] {
+            let err = format(input, 2).unwrap_err();
+            assert_eq!(ErrorKind::Syntax, err.kind(), "{}", input);
+            assert_eq!(Some("declared namespace prefix"), err.expected(), "{}", input);
+            assert_eq!(offset, err.offset(), "{}", input);
+            assert_eq!(err.to_string(), canonicalize(input).unwrap_err().to_string());
+        }
+    }
+
+    #[test]
+    fn close_tag_name() {
+        let err = format("<a><b>x</c></d>", 2).unwrap_err();
+        assert_eq!(ErrorKind::Syntax, err.kind());
+        assert_eq!(Some("`</b>`"), err.expected());
+        assert_eq!(7, err.offset());
+
+        let err = format("<a>\n  <b/>\n</b>", 2).unwrap_err();
+        assert_eq!(Some("`</a>`"), err.expected());
+        assert_eq!((3, 1), (err.line(), err.column()));
+
+        assert!(format_lenient("<a></b>", 2) == "<a></b>");
+    }
+
+    #[test]
+    fn max_depth() {
+        let options = FormatOptions::new().minify(true).max_depth(3);
+        let res = format("<a><b><c/>text</b></a>", options).unwrap();
+        assert_eq!("<a><b><c/>text</b></a>", res);
+
+        let err = format("<a>\n<b><c><d/></c></b></a>", options).unwrap_err();
+        assert_eq!(ErrorKind::TooDeep, err.kind());
+        assert_eq!(10, err.offset());
+        assert_eq!("nesting deeper than 3 levels at line 2, column 7", err.to_string());
+
+        // без ограничения такой документ переполнил бы стек
+        let body = "<a>".repeat(100_000);
+        let err = parse(&body).unwrap_err();
+        assert_eq!(ErrorKind::TooDeep, err.kind());
+        assert_eq!(3 * DEFAULT_MAX_DEPTH, err.offset());
+
+        let err = canonicalize(&body).unwrap_err();
+        assert_eq!(ErrorKind::TooDeep, err.kind());
+        assert_eq!(3 * DEFAULT_MAX_DEPTH, err.offset());
+
+        // ограничение из options и при разборе в дерево, и в канонической форме
+        let body = "<a><b><c><d/></c></b></a>";
+        assert!(parse_with(body, options.max_depth(4)).is_ok());
+        let err = parse_with(body, options).unwrap_err();
+        assert_eq!((ErrorKind::TooDeep, 9), (err.kind(), err.offset()));
+        let err = canonicalize_with(body, false, options).unwrap_err();
+        assert_eq!((ErrorKind::TooDeep, 9), (err.kind(), err.offset()));
+        let err = canonicalize_with(body, true, options).unwrap_err();
+        assert_eq!((ErrorKind::TooDeep, 9), (err.kind(), err.offset()));
+        let err = crate::canonicalize_with(body, options).unwrap_err();
+        assert_eq!((ErrorKind::TooDeep, 9), (err.kind(), err.offset()));
+    }
 }